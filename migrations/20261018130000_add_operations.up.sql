@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS operations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    -- The id the API returned for the write, or the queue message id of a
+    -- message queued without one.
+    operation_id TEXT,
+    user_id TEXT NOT NULL,
+    operation TEXT NOT NULL,
+    status TEXT NOT NULL,
+    failure_reason TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS operations_operation_id_idx
+    ON operations (operation_id);
+
+CREATE INDEX IF NOT EXISTS operations_user_id_idx
+    ON operations (user_id, id);
@@ -0,0 +1,5 @@
+DROP TABLE IF EXISTS pending_email_normalization;
+
+DROP INDEX IF EXISTS users_email_normalized_idx;
+
+ALTER TABLE users DROP COLUMN email_normalized;
@@ -0,0 +1,27 @@
+ALTER TABLE users ADD COLUMN email_normalized TEXT;
+
+-- Only the oldest account keeps the normalized address. Later duplicates are
+-- left NULL so the unique index can be created without failing the migration.
+UPDATE users SET email_normalized = lower(trim(email))
+WHERE rowid IN (
+    SELECT rowid FROM (
+        SELECT rowid, ROW_NUMBER() OVER (
+            PARTITION BY lower(trim(email)) ORDER BY created_at, id
+        ) AS position
+        FROM users
+    )
+    WHERE position = 1
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_normalized_idx
+    ON users (email_normalized);
+
+-- lower(trim(...)) agrees with the application's normalization on printable
+-- ASCII alone. Rows with any other character are queued here and normalized
+-- once by the writer, which empties the queue.
+CREATE TABLE IF NOT EXISTS pending_email_normalization (
+    user_id TEXT PRIMARY KEY NOT NULL
+);
+
+INSERT INTO pending_email_normalization (user_id)
+    SELECT id FROM users WHERE email GLOB '*[^ -~]*';
@@ -0,0 +1,23 @@
+DROP TRIGGER IF EXISTS users_fts_after_delete;
+DROP TRIGGER IF EXISTS users_fts_after_update;
+DROP TRIGGER IF EXISTS users_fts_after_insert;
+DROP TABLE IF EXISTS users_fts;
+
+CREATE TABLE users_old (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    email TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    email_normalized TEXT
+);
+
+INSERT INTO users_old (id, name, email, created_at, updated_at, email_normalized)
+    SELECT id, name, email, created_at, updated_at, email_normalized FROM users;
+
+DROP TABLE users;
+
+ALTER TABLE users_old RENAME TO users;
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_normalized_idx
+    ON users (email_normalized);
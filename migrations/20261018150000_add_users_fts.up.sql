@@ -0,0 +1,48 @@
+-- VACUUM may renumber the rowids of a table without an INTEGER PRIMARY KEY,
+-- which would detach the index from its rows, so `users` gets one.
+CREATE TABLE users_new (
+    seq INTEGER PRIMARY KEY,
+    id TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    email TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    email_normalized TEXT
+);
+
+INSERT INTO users_new (id, name, email, created_at, updated_at, email_normalized)
+    SELECT id, name, email, created_at, updated_at, email_normalized FROM users
+    ORDER BY created_at, id;
+
+DROP TABLE users;
+
+ALTER TABLE users_new RENAME TO users;
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_normalized_idx
+    ON users (email_normalized);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS users_fts USING fts5(
+    name,
+    email,
+    content = 'users',
+    content_rowid = 'seq',
+    tokenize = 'unicode61 remove_diacritics 2',
+    prefix = '2 3'
+);
+
+INSERT INTO users_fts (users_fts) VALUES ('rebuild');
+
+CREATE TRIGGER IF NOT EXISTS users_fts_after_insert AFTER INSERT ON users BEGIN
+    INSERT INTO users_fts (rowid, name, email) VALUES (new.seq, new.name, new.email);
+END;
+
+CREATE TRIGGER IF NOT EXISTS users_fts_after_update AFTER UPDATE OF name, email ON users BEGIN
+    INSERT INTO users_fts (users_fts, rowid, name, email)
+        VALUES ('delete', old.seq, old.name, old.email);
+    INSERT INTO users_fts (rowid, name, email) VALUES (new.seq, new.name, new.email);
+END;
+
+CREATE TRIGGER IF NOT EXISTS users_fts_after_delete AFTER DELETE ON users BEGIN
+    INSERT INTO users_fts (users_fts, rowid, name, email)
+        VALUES ('delete', old.seq, old.name, old.email);
+END;
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS writer_lease (
+    name TEXT PRIMARY KEY NOT NULL,
+    holder_id TEXT NOT NULL,
+    -- Incremented every time the lease changes hands.
+    fencing_token INTEGER NOT NULL,
+    acquired_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME NOT NULL
+);
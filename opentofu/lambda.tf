@@ -18,6 +18,7 @@ resource "aws_lambda_function" "api" {
 
       DATABASE_URL = "sqlite:/mnt/volume/users.db"
       DATABASE_PATH = "/mnt/volume/users.db"
+      BOOKKEEPING_DATABASE_URL = "sqlite:/mnt/volume/api-bookkeeping.db"
 
       AWS_LAMBDA_EXEC_WRAPPER      = "/opt/bootstrap"
       RUST_LOG                     = "info"
@@ -72,6 +73,10 @@ resource "aws_lambda_function" "writer" {
   memory_size      = 128
   architectures    = ["arm64"]
 
+  # One writer at a time: a second instance would only find the lease held
+  # and send its whole batch back towards the dead-letter queue.
+  reserved_concurrent_executions = 1
+
   layers = ["arn:aws:lambda:${local.region}:753240598075:layer:LambdaAdapterLayerArm64:28"]
 
   environment {
@@ -117,6 +122,8 @@ resource "aws_lambda_function" "writer" {
 resource "aws_lambda_event_source_mapping" "writer_sqs" {
   event_source_arn = aws_sqs_queue.writer_queue.arn
   function_name    = aws_lambda_function.writer.arn
-  batch_size       = 1
+  batch_size       = 10
   enabled          = true
+
+  function_response_types = ["ReportBatchItemFailures"]
 }
@@ -1,16 +1,41 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{atomic::Ordering, Arc},
+};
 
 use axum::{
-    extract::{Path, State},
-    http::{StatusCode, Uri},
+    body::Bytes,
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        Path, Query, Request, State,
+    },
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{get, post},
-    Json, Router,
+    routing::{delete, get, patch, post, put},
+    Extension, Json, Router,
 };
-use futures::TryStreamExt;
 use serde_json::json;
+use sqlx::{FromRow, Row};
+use validator::Validate;
+
+use crate::{
+    bulk, db, envelope,
+    error::ApiError,
+    id::generate_xid_string,
+    idempotency::{self, IdempotencyKey, Retryable},
+    listing::{SortField, UserListing},
+    models::*,
+    pagination::{page_size, Cursor},
+    search,
+    sqs::PublishError,
+    streaming::{stream_users, StreamFormat},
+    validation::{field_errors, normalize_email},
+    writer::{self, ApplyError},
+};
 
-use crate::{db, db::set_default_env_var, id::generate_xid_string, models::*, sqs};
+/// Port of the HTTP mode when `PORT` is not set.
+pub const DEFAULT_PORT: u16 = 9989;
 
 async fn root() -> impl IntoResponse {
     (
@@ -26,80 +51,521 @@ async fn health_check() -> impl IntoResponse {
 }
 
 async fn load_users(
+    headers: HeaderMap,
+    params: Result<Query<ListUsersParams>, QueryRejection>,
     State(state): State<Arc<AppState>>,
-) -> Result<Json<MultipleUsersResult>, ApiError> {
-    let users_result = sqlx::query_as::<_, User>("SELECT id, name, email FROM users")
-        .fetch(&state.pool)
-        .map_ok(|u| u)
-        .try_collect::<Vec<User>>()
-        .await;
+) -> Result<Response, ApiError> {
+    let Query(params) = params?;
+    let stream = params.stream.unwrap_or(false);
+    let listing = UserListing::from_params(params)?;
+
+    if let Some(format) = StreamFormat::negotiate(&headers, stream) {
+        return Ok(stream_users(state.pool.clone(), listing, format));
+    }
+
+    let rows = listing.page_query().build().fetch_all(&state.pool).await?;
+
+    let mut page = Vec::with_capacity(rows.len());
+    for row in &rows {
+        let user = User::from_row(row)?;
+        let sort_key: Option<String> = row.try_get("sort_key")?;
+        page.push((user, sort_key));
+    }
 
-    match users_result {
-        Ok(users) => Ok(Json(MultipleUsersResult { users })),
-        Err(_) => Err(ApiError::SomethingElseWentWrong),
+    let next_cursor = if page.len() > listing.limit as usize {
+        page.truncate(listing.limit as usize);
+        page.last().map(|(user, sort_key)| {
+            Cursor {
+                id: user.id.clone(),
+                key: match listing.sort.field {
+                    SortField::Id => None,
+                    _ => sort_key.clone(),
+                },
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
+
+    let users = page.into_iter().map(|(user, _)| user).collect();
+    let mut body = serde_json::to_value(MultipleUsersResult { users, next_cursor })
+        .map_err(|_| ApiError::Internal)?;
+    if let Some(users) = body["users"].as_array_mut() {
+        listing.project(users);
     }
+
+    Ok(Json(body).into_response())
+}
+
+/// Downloads every user matching the listing filters; `limit` and `cursor`
+/// are ignored.
+async fn export_users(
+    export: Result<Query<ExportUsersParams>, QueryRejection>,
+    params: Result<Query<ListUsersParams>, QueryRejection>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, ApiError> {
+    let Query(export) = export?;
+    let format = match export.format.as_str() {
+        "csv" => StreamFormat::Csv,
+        "ndjson" => StreamFormat::NdJson,
+        other => {
+            return Err(ApiError::BadRequest(format!(
+                "unsupported export format {}",
+                other
+            )))
+        }
+    };
+
+    let Query(params) = params?;
+    let listing = UserListing {
+        after: None,
+        ..UserListing::from_params(params)?
+    };
+
+    let mut response = stream_users(state.pool.clone(), listing, format);
+    let disposition = format!("attachment; filename=\"users.{}\"", format.extension());
+    response.headers_mut().insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_str(&disposition).map_err(|_| ApiError::Internal)?,
+    );
+
+    Ok(response)
+}
+
+async fn search_users(
+    params: Result<Query<SearchUsersParams>, QueryRejection>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<UserSearchResult>, ApiError> {
+    let Query(params) = params?;
+    let expression = search::match_expression(&params.q)
+        .ok_or_else(|| ApiError::BadRequest("search query has no terms".to_string()))?;
+
+    let users = search::search_users(&state.pool, &expression, page_size(params.limit)).await?;
+
+    Ok(Json(UserSearchResult { users }))
 }
 
 async fn find_user(
     Path(id): Path<String>,
     State(state): State<Arc<AppState>>,
 ) -> Result<Json<User>, ApiError> {
-    let users_result = sqlx::query_as::<_, User>("SELECT id, name, email FROM users WHERE id = $1")
-        .bind(&id)
-        .fetch_one(&state.pool)
-        .await;
+    let users_result = sqlx::query_as::<_, User>(
+        "SELECT id, name, email, created_at, updated_at FROM users WHERE id = $1",
+    )
+    .bind(&id)
+    .fetch_one(&state.pool)
+    .await;
+
+    Ok(Json(users_result?))
+}
 
-    match users_result {
-        Ok(user) => Ok(Json(user)),
-        Err(_) => Err(ApiError::SomethingElseWentWrong),
+/// Reports what became of a write accepted under `id`. The API records each
+/// operation as pending in its bookkeeping database before publishing it, and
+/// the writer records the outcome in the shared one. Ids the API never issued,
+/// or that have been purged, are not found.
+async fn operation_status(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<OperationStatusResponse>, ApiError> {
+    let query =
+        "SELECT user_id, operation, status, failure_reason FROM operations WHERE operation_id = $1";
+    let mut recorded: Option<(String, String, OperationStatus, Option<String>)> =
+        sqlx::query_as(query)
+            .bind(&id)
+            .fetch_optional(&state.pool)
+            .await?;
+    if recorded.is_none() {
+        recorded = sqlx::query_as(query)
+            .bind(&id)
+            .fetch_optional(&state.bookkeeping)
+            .await?;
     }
+
+    let (user_id, operation, status, failure_reason) = recorded.ok_or(ApiError::NotFound)?;
+
+    Ok(Json(OperationStatusResponse {
+        id,
+        status,
+        user_id,
+        operation,
+        failure_reason,
+    }))
 }
 
 async fn create_user(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<CreateUserRequest>,
-) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
-    let id = generate_xid_string();
-    let queued = QueuedUser::from_create_request(&payload, id.clone());
-
-    let queue_url = match std::env::var("SQS_QUEUE_URL") {
-        Ok(url) => url,
-        Err(_) => {
-            // No queue configured: write directly (local dev fallback)
-            let _ = sqlx::query("INSERT INTO users (id, name, email) VALUES ($1, $2, $3)")
-                .bind(&queued.id)
-                .bind(&queued.name)
-                .bind(&queued.email)
-                .execute(&state.pool)
-                .await
-                .map_err(|_| ApiError::SomethingWentWrong)?;
+    key: IdempotencyKey,
+    payload: Result<Json<CreateUserRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let payload = payload?.0.normalized();
+    let fingerprint = idempotency::fingerprint("POST", "/users", &to_json_bytes(&payload)?);
+
+    idempotency::run(&state.bookkeeping, key, fingerprint, async {
+        payload.validate()?;
+        ensure_email_available(&state, &payload.email, None).await?;
+
+        let id = generate_xid_string();
+        let operation = QueuedOperation::CreateUser(QueuedUser::from_create_request(&payload, id));
+
+        enqueue_operation(&state, operation).await
+    })
+    .await
+}
+
+/// Imports many users from a JSON array or a CSV upload. Every row is
+/// validated on its own: valid rows get an id and are enqueued together,
+/// invalid ones are reported with their errors and skipped.
+async fn bulk_create_users(
+    Path(action): Path<String>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+    key: IdempotencyKey,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    if action != ":bulk" {
+        return Err(ApiError::NotFound);
+    }
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let fingerprint = idempotency::fingerprint("POST", "/users:bulk", &body);
 
-            return Ok((
-                StatusCode::ACCEPTED,
-                Json(json!({ "id": id, "status": "accepted" })),
-            ));
+    idempotency::run(
+        &state.bookkeeping,
+        key,
+        fingerprint,
+        import_users(&state, content_type, &body),
+    )
+    .await
+}
+
+async fn import_users(
+    state: &AppState,
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<
+    (
+        StatusCode,
+        Option<Extension<Retryable>>,
+        Json<BulkImportResponse>,
+    ),
+    ApiError,
+> {
+    let rows = bulk::parse_rows(content_type, body)?;
+
+    let mut results = Vec::with_capacity(rows.len());
+    let mut operations = Vec::new();
+    let mut seen_emails = HashSet::new();
+
+    for (index, row) in rows.into_iter().enumerate() {
+        let row_result = match row {
+            Ok(payload) => validate_bulk_row(state, payload.normalized(), &mut seen_emails).await?,
+            Err(errors) => Err(errors),
+        };
+
+        match row_result {
+            Ok(payload) => {
+                let id = generate_xid_string();
+                let operation_id = generate_xid_string();
+                let user = QueuedUser::from_create_request(&payload, id.clone());
+                operations.push((
+                    results.len(),
+                    operation_id.clone(),
+                    QueuedOperation::CreateUser(user),
+                ));
+                results.push(BulkRowResult {
+                    row: index + 1,
+                    id: Some(id),
+                    operation_id: Some(operation_id),
+                    status: BulkRowStatus::Accepted,
+                    errors: Default::default(),
+                });
+            }
+            Err(errors) => results.push(BulkRowResult {
+                row: index + 1,
+                id: None,
+                operation_id: None,
+                status: BulkRowStatus::Rejected,
+                errors,
+            }),
         }
-    };
+    }
+
+    let mut retryable = false;
+    for (index, e) in enqueue_operations(state, &operations).await? {
+        let (reason, transient) = match e {
+            PublishError::Failed(_) => ("could not be enqueued, try again".to_string(), true),
+            PublishError::Rejected(ApplyError::Lease(_) | ApplyError::Database(_)) => {
+                (format!("{}, try again", e), true)
+            }
+            PublishError::Rejected(e) => (e.to_string(), false),
+        };
+        retryable |= transient;
 
-    let body = serde_json::to_string(&queued).map_err(|_| ApiError::SomethingWentWrong)?;
+        let result = &mut results[index];
+        result.id = None;
+        result.operation_id = None;
+        result.status = BulkRowStatus::Rejected;
+        result.errors = bulk::row_error(reason);
+    }
 
-    sqs::publish_message(&queue_url, &body).await.map_err(|e| {
-        tracing::error!("Failed to publish to SQS: {}", e);
-        ApiError::SomethingWentWrong
-    })?;
+    let accepted = results
+        .iter()
+        .filter(|result| result.status == BulkRowStatus::Accepted)
+        .count();
 
     Ok((
         StatusCode::ACCEPTED,
-        Json(json!({ "id": id, "status": "accepted" })),
+        retryable.then_some(Extension(Retryable)),
+        Json(BulkImportResponse {
+            accepted,
+            rejected: results.len() - accepted,
+            results,
+        }),
     ))
 }
 
-async fn fallback_handler(uri: Uri) -> impl IntoResponse {
-    tracing::error!("No route for {}", uri);
-    (
-        StatusCode::NOT_FOUND,
-        Json(json!({ "message": format!("No route for {}", uri) })),
+/// Validates one bulk row, including email uniqueness against the database
+/// and against the rows before it.
+async fn validate_bulk_row(
+    state: &AppState,
+    payload: CreateUserRequest,
+    seen_emails: &mut HashSet<String>,
+) -> Result<Result<CreateUserRequest, bulk::RowErrors>, ApiError> {
+    if let Err(errors) = payload.validate() {
+        return Ok(Err(field_errors(&errors)));
+    }
+
+    let email = normalize_email(&payload.email);
+    if !seen_emails.insert(email.clone()) {
+        return Ok(Err(BTreeMap::from([(
+            "email".to_string(),
+            vec!["appears more than once in this import".to_string()],
+        )])));
+    }
+
+    match ensure_email_available(state, &email, None).await {
+        Ok(()) => Ok(Ok(payload)),
+        Err(ApiError::Conflict(reason)) => {
+            Ok(Err(BTreeMap::from([("email".to_string(), vec![reason])])))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn replace_user(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    key: IdempotencyKey,
+    payload: Result<Json<CreateUserRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let payload = payload?.0.normalized();
+    let path = format!("/users/{}", id);
+    let fingerprint = idempotency::fingerprint("PUT", &path, &to_json_bytes(&payload)?);
+
+    idempotency::run(&state.bookkeeping, key, fingerprint, async {
+        payload.validate()?;
+        ensure_email_available(&state, &payload.email, Some(&id)).await?;
+
+        let update = UpdateUserRequest {
+            name: Some(payload.name.clone()),
+            email: Some(payload.email.clone()),
+        };
+        let operation =
+            QueuedOperation::UpdateUser(QueuedUserUpdate::from_update_request(&update, id.clone()));
+
+        enqueue_operation(&state, operation).await
+    })
+    .await
+}
+
+async fn update_user(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    key: IdempotencyKey,
+    payload: Result<Json<UpdateUserRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let payload = payload?.0.normalized();
+    let path = format!("/users/{}", id);
+    let fingerprint = idempotency::fingerprint("PATCH", &path, &to_json_bytes(&payload)?);
+
+    idempotency::run(&state.bookkeeping, key, fingerprint, async {
+        payload.validate()?;
+        if let Some(email) = &payload.email {
+            ensure_email_available(&state, email, Some(&id)).await?;
+        }
+
+        let operation = QueuedOperation::UpdateUser(QueuedUserUpdate::from_update_request(
+            &payload,
+            id.clone(),
+        ));
+
+        enqueue_operation(&state, operation).await
+    })
+    .await
+}
+
+async fn delete_user(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    key: IdempotencyKey,
+) -> Result<Response, ApiError> {
+    let fingerprint = idempotency::fingerprint("DELETE", &format!("/users/{}", id), &[]);
+    let operation = QueuedOperation::DeleteUser(QueuedUserDelete { id });
+
+    idempotency::run(
+        &state.bookkeeping,
+        key,
+        fingerprint,
+        enqueue_operation(&state, operation),
     )
+    .await
+}
+
+fn to_json_bytes<T: serde::Serialize>(payload: &T) -> Result<Vec<u8>, ApiError> {
+    serde_json::to_vec(payload).map_err(|_| ApiError::Internal)
+}
+
+/// Rejects early an email address already used by another user. The writer
+/// enforces uniqueness too, since writes queued concurrently can still race.
+async fn ensure_email_available(
+    state: &AppState,
+    email: &str,
+    user_id: Option<&str>,
+) -> Result<(), ApiError> {
+    let taken: Option<i64> =
+        sqlx::query_scalar("SELECT 1 FROM users WHERE email_normalized = $1 AND id IS NOT $2")
+            .bind(normalize_email(email))
+            .bind(user_id)
+            .fetch_optional(&state.pool)
+            .await?;
+
+    match taken {
+        Some(_) => Err(ApiError::Conflict(
+            "email address is already taken".to_string(),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Publishes many operations at once. Each operation is tagged with the
+/// caller's index and its operation id, and the indexes that were not
+/// published are returned with the error.
+async fn enqueue_operations(
+    state: &AppState,
+    operations: &[(usize, String, QueuedOperation)],
+) -> Result<Vec<(usize, PublishError)>, ApiError> {
+    let mut messages = Vec::with_capacity(operations.len());
+    for (index, operation_id, operation) in operations {
+        let body =
+            envelope::encode_message(operation_id, operation).map_err(|_| ApiError::Internal)?;
+        messages.push((index.to_string(), body));
+    }
+
+    let pending: Vec<_> = operations
+        .iter()
+        .map(|(_, operation_id, operation)| (operation_id.as_str(), operation))
+        .collect();
+    record_pending(state, &pending).await?;
+
+    let failures: Vec<(usize, PublishError)> = state
+        .publisher
+        .publish_batch(&messages)
+        .await
+        .into_iter()
+        .filter_map(|(id, e)| id.parse().ok().map(|index| (index, e)))
+        .collect();
+
+    for (index, _) in &failures {
+        if let Some((_, operation_id, _)) = operations.iter().find(|(i, _, _)| i == index) {
+            forget_pending(state, operation_id).await;
+        }
+    }
+
+    Ok(failures)
+}
+
+/// Records operations as pending before they are published, so that their
+/// status can be polled as soon as their id is returned. Operations the
+/// writer has not reported on within the retention period are purged.
+async fn record_pending(
+    state: &AppState,
+    operations: &[(&str, &QueuedOperation)],
+) -> Result<(), ApiError> {
+    let mut tx = state.bookkeeping.begin().await?;
+
+    sqlx::query("DELETE FROM operations WHERE updated_at < datetime('now', $1)")
+        .bind(format!("-{} days", writer::RETENTION_DAYS))
+        .execute(&mut *tx)
+        .await?;
+
+    for &(operation_id, operation) in operations {
+        sqlx::query(
+            "INSERT INTO operations (operation_id, user_id, operation, status) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (operation_id) DO NOTHING",
+        )
+        .bind(operation_id)
+        .bind(operation.user_id())
+        .bind(operation.operation_type())
+        .bind(OperationStatus::Pending)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Drops a pending operation that could not be published, as its id is not
+/// returned.
+async fn forget_pending(state: &AppState, operation_id: &str) {
+    if let Err(e) = sqlx::query("DELETE FROM operations WHERE operation_id = $1 AND status = $2")
+        .bind(operation_id)
+        .bind(OperationStatus::Pending)
+        .execute(&state.bookkeeping)
+        .await
+    {
+        tracing::warn!("Failed to drop pending operation {}: {}", operation_id, e);
+    }
+}
+
+/// Publishes one operation under a new operation id, which the response
+/// returns along with the URL to poll for its status.
+async fn enqueue_operation(
+    state: &AppState,
+    operation: QueuedOperation,
+) -> Result<
+    (
+        StatusCode,
+        [(header::HeaderName, String); 1],
+        Json<serde_json::Value>,
+    ),
+    ApiError,
+> {
+    let id = operation.user_id().to_string();
+    let operation_id = generate_xid_string();
+    let body =
+        envelope::encode_message(&operation_id, &operation).map_err(|_| ApiError::Internal)?;
+
+    record_pending(state, &[(operation_id.as_str(), &operation)]).await?;
+    if let Err(e) = state.publisher.publish(&body).await {
+        forget_pending(state, &operation_id).await;
+        return Err(e.into());
+    }
+
+    Ok((
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, format!("/operations/{}", operation_id))],
+        Json(json!({ "id": id, "operation_id": operation_id, "status": "accepted" })),
+    ))
+}
+
+async fn fallback_handler(uri: Uri) -> ApiError {
+    tracing::error!("No route for {}", uri);
+    ApiError::NotFound
 }
 
 pub fn create_router() -> Router<Arc<AppState>> {
@@ -107,50 +573,69 @@ pub fn create_router() -> Router<Arc<AppState>> {
         .route("/", get(root))
         .route("/health-check", get(health_check))
         .route("/users", get(load_users))
+        .route("/users/search", get(search_users))
         .route("/users/:id", get(find_user))
         .route("/users", post(create_user))
+        // The router has no escape for `:`, so `/users:bulk` is matched as a
+        // `/users` suffix and the action is checked in the handler.
+        .route("/users:action", post(bulk_create_users))
+        .route("/users/:id", put(replace_user))
+        .route("/users/:id", patch(update_user))
+        .route("/users/:id", delete(delete_user))
+        .route("/exports/users", get(export_users))
+        .route("/operations/:id", get(operation_status))
         .fallback(fallback_handler)
 }
 
-pub async fn serve_api(state: Arc<AppState>) {
-    tracing_subscriber::fmt::init();
+/// The API router with its state, answering 503 until the database is
+/// migrated.
+pub fn app(state: Arc<AppState>) -> Router {
+    create_router()
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_schema,
+        ))
+        .with_state(state)
+}
+
+/// Holds back requests while the schema does not match this build, as when
+/// the API starts before the writer has created or migrated the database.
+/// The schema is checked again on each request until it is ready. The health
+/// check is always answered, so that the instance is not restarted meanwhile.
+pub async fn require_schema(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if !state.schema_ready.load(Ordering::Acquire) && request.uri().path() != "/health-check" {
+        if let Err(e) = db::verify_schema(&state.pool).await {
+            tracing::warn!("{}", e);
+            return Err(ApiError::NotMigrated);
+        }
+        tracing::info!("Database is migrated, serving requests");
+        state.schema_ready.store(true, Ordering::Release);
+    }
+
+    Ok(next.run(request).await)
+}
 
-    set_default_env_var("PORT", "9989");
-    let port = std::env::var("PORT").expect("Application port not defined");
+pub async fn serve_api(state: Arc<AppState>) {
+    let port = state.config.port.unwrap_or(DEFAULT_PORT);
 
-    let address = std::net::SocketAddr::from(([0, 0, 0, 0], port.parse().unwrap()));
+    let address = std::net::SocketAddr::from(([0, 0, 0, 0], port));
     let listener = tokio::net::TcpListener::bind(address).await.unwrap();
     tracing::info!("API listening on {}", address);
 
-    axum::serve(listener, create_router().with_state(state.clone()))
+    axum::serve(listener, app(state.clone()))
         .with_graceful_shutdown(db::shutdown_signal(state))
         .await
         .unwrap();
 }
 
-enum ApiError {
-    SomethingWentWrong,
-    SomethingElseWentWrong,
-}
-
-impl IntoResponse for ApiError {
-    fn into_response(self) -> Response {
-        let (status, body) = match self {
-            ApiError::SomethingWentWrong => {
-                (StatusCode::INTERNAL_SERVER_ERROR, "something went wrong")
-            }
-            ApiError::SomethingElseWentWrong => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "something else went wrong",
-            ),
-        };
-        (status, Json(json!({ "message": body }))).into_response()
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{config::Config, writer};
     use axum::{body::Body, http::Request};
     use http_body_util::BodyExt;
     use serde_json::Value;
@@ -159,7 +644,7 @@ mod tests {
 
     #[sqlx::test]
     async fn health_check_should_return_200(pool: SqlitePool) {
-        let state = Arc::new(AppState { pool });
+        let state = Arc::new(AppState::new(pool));
         let app = create_router().with_state(state);
 
         let response = app
@@ -178,9 +663,33 @@ mod tests {
         assert_eq!(body, json!({ "message": "ok" }));
     }
 
+    #[sqlx::test(migrations = false)]
+    async fn requests_should_wait_for_the_schema(pool: SqlitePool) {
+        let state = Arc::new(AppState {
+            schema_ready: false.into(),
+            ..AppState::new(pool)
+        });
+        let get = |uri: &'static str| {
+            app(state.clone()).oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/users").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "not_migrated");
+        assert_eq!(get("/health-check").await.unwrap().status(), StatusCode::OK);
+
+        sqlx::migrate!().run(&state.pool).await.unwrap();
+
+        assert_eq!(get("/users").await.unwrap().status(), StatusCode::OK);
+        assert!(state.schema_ready.load(Ordering::Acquire));
+    }
+
     #[sqlx::test]
     async fn root_should_return_200(pool: SqlitePool) {
-        let state = Arc::new(AppState { pool });
+        let state = Arc::new(AppState::new(pool));
         let app = create_router().with_state(state);
 
         let response = app
@@ -199,7 +708,7 @@ mod tests {
 
     #[sqlx::test]
     async fn load_users_should_return_200(pool: SqlitePool) {
-        let state = Arc::new(AppState { pool });
+        let state = Arc::new(AppState::new(pool));
         let id = generate_xid_string();
         let name = format!("user-{}", &id[..8]);
         let email = format!("{}@example.com", &id[..8]);
@@ -236,25 +745,22 @@ mod tests {
     }
 
     #[sqlx::test]
-    async fn find_user_should_return_200(pool: SqlitePool) {
-        let state = Arc::new(AppState { pool });
+    async fn users_should_expose_rfc3339_timestamps(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
         let id = generate_xid_string();
-        let name = format!("user-{}", &id[..8]);
-        let email = format!("{}@example.com", &id[..8]);
 
-        sqlx::query("INSERT INTO users (id, name, email) VALUES ($1, $2, $3)")
-            .bind(&id)
-            .bind(&name)
-            .bind(&email)
-            .execute(&state.pool)
-            .await
-            .unwrap();
+        sqlx::query(
+            "INSERT INTO users (id, name, email, created_at, updated_at) VALUES ($1, 'ts', 'ts@example.com', '2024-01-02 03:04:05', '2024-01-02 03:04:05')",
+        )
+        .bind(&id)
+        .execute(&state.pool)
+        .await
+        .unwrap();
 
         let app = create_router().with_state(state.clone());
         let response = app
             .oneshot(
                 Request::builder()
-                    .method("GET")
                     .uri(format!("/users/{}", id))
                     .body(Body::empty())
                     .unwrap(),
@@ -262,62 +768,998 @@ mod tests {
             .await
             .unwrap();
 
-        assert_eq!(response.status(), StatusCode::OK);
         let body = response.into_body().collect().await.unwrap().to_bytes();
         let body: Value = serde_json::from_slice(&body).unwrap();
-        let user: User = serde_json::from_value(body).unwrap();
-        assert_eq!(user.id, id);
-        assert_eq!(user.name, name);
-        assert_eq!(user.email, email);
+        assert_eq!(body["created_at"], "2024-01-02T03:04:05Z");
+        assert_eq!(body["updated_at"], "2024-01-02T03:04:05Z");
+
+        let operation = QueuedOperation::UpdateUser(QueuedUserUpdate {
+            id: id.clone(),
+            name: Some("renamed".to_string()),
+            email: None,
+        });
+        writer::apply_direct(
+            &state.pool,
+            &state.lease,
+            &generate_xid_string(),
+            &operation,
+        )
+        .await
+        .unwrap();
+
+        let user = sqlx::query_as::<_, User>(
+            "SELECT id, name, email, created_at, updated_at FROM users WHERE id = $1",
+        )
+        .bind(&id)
+        .fetch_one(&state.pool)
+        .await
+        .unwrap();
+        assert_eq!(user.created_at.to_rfc3339(), "2024-01-02T03:04:05+00:00");
+        assert!(user.updated_at > user.created_at);
+
+        let response = create_router()
+            .with_state(state.clone())
+            .oneshot(
+                Request::builder()
+                    .uri("/users?updated_after=2025-01-01T00:00:00Z&fields=id,updated_at")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["users"].as_array().unwrap().len(), 1);
+        assert_eq!(body["users"][0]["id"], id);
+        assert!(body["users"][0]["updated_at"].is_string());
     }
 
     #[sqlx::test]
-    async fn create_user_should_return_202(pool: SqlitePool) {
-        let state = Arc::new(AppState { pool });
-        let app = create_router().with_state(state.clone());
+    async fn load_users_should_paginate_with_cursor(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let mut ids = Vec::new();
+        for i in 0..5 {
+            let id = generate_xid_string();
+            sqlx::query("INSERT INTO users (id, name, email) VALUES ($1, $2, $3)")
+                .bind(&id)
+                .bind(format!("page-{}", i))
+                .bind(format!("page-{}@example.com", i))
+                .execute(&state.pool)
+                .await
+                .unwrap();
+            ids.push(id);
+        }
 
-        let user = CreateUserRequest {
-            name: "test-user".to_string(),
-            email: "test@example.com".to_string(),
-        };
+        let mut seen = Vec::new();
+        let mut uri = "/users?limit=2".to_string();
+        loop {
+            let response = create_router()
+                .with_state(state.clone())
+                .oneshot(Request::builder().uri(&uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
 
-        let response = app
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let page: MultipleUsersResult = serde_json::from_slice(&body).unwrap();
+            assert!(page.users.len() <= 2);
+            seen.extend(page.users.into_iter().map(|u| u.id));
+
+            match page.next_cursor {
+                Some(cursor) => uri = format!("/users?limit=2&cursor={}", cursor),
+                None => break,
+            }
+        }
+
+        assert_eq!(seen, ids);
+    }
+
+    #[sqlx::test]
+    async fn load_users_should_stream_ndjson_and_json_array(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        for i in 0..3 {
+            sqlx::query("INSERT INTO users (id, name, email) VALUES ($1, $2, $3)")
+                .bind(format!("stream-{}", i))
+                .bind(format!("stream {}", i))
+                .bind(format!("stream-{}@example.com", i))
+                .execute(&state.pool)
+                .await
+                .unwrap();
+        }
+
+        let response = create_router()
+            .with_state(state.clone())
             .oneshot(
                 Request::builder()
-                    .method("POST")
-                    .uri("/users")
-                    .header("Content-Type", "application/json")
-                    .body(Body::from(serde_json::to_string(&user).unwrap()))
+                    .uri("/users?limit=1&fields=id,name")
+                    .header(header::ACCEPT, "application/x-ndjson")
+                    .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-ndjson"
+        );
 
-        assert_eq!(response.status(), StatusCode::ACCEPTED);
         let body = response.into_body().collect().await.unwrap().to_bytes();
-        let body: Value = serde_json::from_slice(&body).unwrap();
-        assert!(body["id"].as_str().unwrap().len() == 24);
-        assert_eq!(body["status"], "accepted");
+        let lines: Vec<Value> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], json!({"id": "stream-0", "name": "stream 0"}));
+
+        let response = create_router()
+            .with_state(state)
+            .oneshot(
+                Request::builder()
+                    .uri("/users?stream=true&sort=-id&email=stream-")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let users: Vec<User> = serde_json::from_slice(&body).unwrap();
+        let ids: Vec<&str> = users.iter().map(|u| u.id.as_str()).collect();
+        assert_eq!(ids, vec!["stream-2", "stream-1", "stream-0"]);
     }
 
     #[sqlx::test]
-    async fn unknown_api_should_be_handled_by_fallback_handler(pool: SqlitePool) {
-        let state = Arc::new(AppState { pool });
-        let app = create_router().with_state(state);
+    async fn export_users_should_download_filtered_csv(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        for (id, name, email) in [
+            ("e1", "Ada, Countess", "ada@example.com"),
+            ("e2", "Grace", "grace@example.org"),
+        ] {
+            sqlx::query(
+                "INSERT INTO users (id, name, email, email_normalized) VALUES ($1, $2, $3, $3)",
+            )
+            .bind(id)
+            .bind(name)
+            .bind(email)
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        }
 
-        let response = app
+        let response = create_router()
+            .with_state(state.clone())
             .oneshot(
                 Request::builder()
-                    .uri("/does-not-exist")
+                    .uri("/exports/users?format=csv&email_domain=example.com&fields=id,name")
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_DISPOSITION],
+            "attachment; filename=\"users.csv\""
+        );
 
-        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"id,name\r\ne1,\"Ada, Countess\"\r\n");
+
+        let response = create_router()
+            .with_state(state)
+            .oneshot(
+                Request::builder()
+                    .uri("/exports/users?format=xml")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn bulk_create_users_should_report_row_results(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        sqlx::query(
+            "INSERT INTO users (id, name, email, email_normalized) VALUES ('taken', 'Taken', 'taken@example.com', 'taken@example.com')",
+        )
+        .execute(&state.pool)
+        .await
+        .unwrap();
+
+        let csv = "name,email\nAda,ada@example.com\n,blank@example.com\nAda Again,ADA@example.com\nT,taken@example.com\n";
+        let response = create_router()
+            .with_state(state.clone())
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/users:bulk")
+                    .header(header::CONTENT_TYPE, "text/csv")
+                    .body(Body::from(csv))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let import: BulkImportResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!((import.accepted, import.rejected), (1, 3));
+
+        let statuses: Vec<_> = import
+            .results
+            .iter()
+            .map(|r| (r.row, r.status.clone()))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (1, BulkRowStatus::Accepted),
+                (2, BulkRowStatus::Rejected),
+                (3, BulkRowStatus::Rejected),
+                (4, BulkRowStatus::Rejected),
+            ]
+        );
+        assert!(import.results[1].errors.contains_key("name"));
+        assert!(import.results[2].errors.contains_key("email"));
+        assert_eq!(
+            import.results[3].errors["email"],
+            vec!["email address is already taken"]
+        );
+
+        let id = import.results[0].id.clone().unwrap();
+        let name: String = sqlx::query_scalar("SELECT name FROM users WHERE id = $1")
+            .bind(&id)
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+        assert_eq!(name, "Ada");
+
+        let response = create_router()
+            .with_state(state)
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/users:other")
+                    .header(header::CONTENT_TYPE, "text/csv")
+                    .body(Body::from("name,email\n"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+    }
+
+    #[sqlx::test]
+    async fn create_user_with_idempotency_key_should_replay_first_response(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let post = |body: &'static str| {
+            create_router().with_state(state.clone()).oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/users")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(idempotency::IDEMPOTENCY_KEY, "retry-1")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+        let payload = r#"{"name": "Idem", "email": "idem@example.com"}"#;
+
+        let first = post(payload).await.unwrap();
+        assert_eq!(first.status(), StatusCode::ACCEPTED);
+        assert!(first
+            .headers()
+            .get(idempotency::IDEMPOTENT_REPLAYED)
+            .is_none());
+        let location = first.headers()[header::LOCATION].clone();
+        let first = first.into_body().collect().await.unwrap().to_bytes();
+
+        let retry = post(payload).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::ACCEPTED);
+        assert_eq!(retry.headers()[idempotency::IDEMPOTENT_REPLAYED], "true");
+        assert_eq!(retry.headers()[header::LOCATION], location);
+        assert_eq!(retry.headers()[header::CONTENT_TYPE], "application/json");
+        let retry = retry.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(first, retry);
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+
+        let other = post(r#"{"name": "Other", "email": "other@example.com"}"#)
+            .await
+            .unwrap();
+        assert_eq!(other.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[sqlx::test]
+    async fn read_only_api_should_record_idempotency_keys_for_bookkeeping(pool: SqlitePool) {
+        let name = generate_xid_string();
+        let spool = std::env::temp_dir().join(format!("bookkeeping-{}.jsonl", name));
+        let bookkeeping = std::env::temp_dir().join(format!("bookkeeping-{}.db", name));
+        let config = Config {
+            bookkeeping_database_url: format!("sqlite:{}", bookkeeping.display()),
+            ..Config::default()
+        };
+        let state = Arc::new(AppState {
+            bookkeeping: db::connect_bookkeeping(&config).await.unwrap(),
+            publisher: Box::new(crate::sqs::SpoolPublisher::open(&spool).await.unwrap()),
+            ..AppState::new(pool)
+        });
+        let post = |body: Value| {
+            create_router().with_state(state.clone()).oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/users")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(idempotency::IDEMPOTENCY_KEY, "read-only-retry")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+        };
+        let payload = json!({ "name": "Retry", "email": "retry@example.com" });
+
+        let first = post(payload.clone()).await.unwrap();
+        assert_eq!(first.status(), StatusCode::ACCEPTED);
+        let first = first.into_body().collect().await.unwrap().to_bytes();
+        let retry = post(payload).await.unwrap();
+        assert_eq!(retry.headers()[idempotency::IDEMPOTENT_REPLAYED], "true");
+        assert_eq!(first, retry.into_body().collect().await.unwrap().to_bytes());
+
+        let other = post(json!({ "name": "Other", "email": "other@example.com" }))
+            .await
+            .unwrap();
+        assert_eq!(other.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let count = |pool: SqlitePool| async move {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM idempotency_keys")
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        };
+        assert_eq!(count(state.pool.clone()).await, 0);
+        assert_eq!(count(state.bookkeeping.clone()).await, 1);
+        assert_eq!(std::fs::read_to_string(&spool).unwrap().lines().count(), 1);
+
+        state.bookkeeping.close().await;
+        std::fs::remove_file(&spool).unwrap();
+        std::fs::remove_file(&bookkeeping).unwrap();
+    }
+
+    #[sqlx::test]
+    async fn bulk_import_with_transient_failures_should_not_be_replayed(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let holder = crate::lease::WriterLease::new(Default::default());
+        let mut tx = state.pool.begin().await.unwrap();
+        holder.acquire(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+
+        let post = || {
+            create_router().with_state(state.clone()).oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/users:bulk")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(idempotency::IDEMPOTENCY_KEY, "bulk-1")
+                    .body(Body::from(
+                        json!([{ "name": "Ada", "email": "ada@example.com" }]).to_string(),
+                    ))
+                    .unwrap(),
+            )
+        };
+
+        let first = post().await.unwrap();
+        assert_eq!(first.status(), StatusCode::ACCEPTED);
+        let body = first.into_body().collect().await.unwrap().to_bytes();
+        let import: BulkImportResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!((import.accepted, import.rejected), (0, 1));
+
+        holder.release(&state.pool).await;
+
+        let retry = post().await.unwrap();
+        assert!(retry
+            .headers()
+            .get(idempotency::IDEMPOTENT_REPLAYED)
+            .is_none());
+        let body = retry.into_body().collect().await.unwrap().to_bytes();
+        let import: BulkImportResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!((import.accepted, import.rejected), (1, 0));
+    }
+
+    #[sqlx::test]
+    async fn load_users_with_invalid_cursor_should_return_400(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let app = create_router().with_state(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/users?cursor=zzz")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn load_users_should_filter_sort_and_project(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        for (name, email) in [
+            ("carol", "carol@acme.test"),
+            ("alice", "alice@acme.test"),
+            ("bob", "bob@other.test"),
+            ("dave", "dave@acme.test"),
+        ] {
+            let operation = QueuedOperation::CreateUser(QueuedUser {
+                id: generate_xid_string(),
+                name: name.to_string(),
+                email: email.to_string(),
+            });
+            writer::apply_direct(
+                &state.pool,
+                &state.lease,
+                &generate_xid_string(),
+                &operation,
+            )
+            .await
+            .unwrap();
+        }
+        // A duplicate left without a normalized email by the migration.
+        sqlx::query("INSERT INTO users (id, name, email) VALUES ($1, 'erin', 'Erin@Acme.test')")
+            .bind(generate_xid_string())
+            .execute(&state.pool)
+            .await
+            .unwrap();
+
+        let mut names = Vec::new();
+        let mut uri = "/users?email_domain=ACME.test&sort=-name&fields=name&limit=2".to_string();
+        loop {
+            let response = create_router()
+                .with_state(state.clone())
+                .oneshot(Request::builder().uri(&uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            for user in body["users"].as_array().unwrap() {
+                assert_eq!(user.as_object().unwrap().len(), 1);
+                names.push(user["name"].as_str().unwrap().to_string());
+            }
+
+            match body["next_cursor"].as_str() {
+                Some(cursor) => {
+                    uri = format!(
+                        "/users?email_domain=ACME.test&sort=-name&fields=name&limit=2&cursor={}",
+                        cursor
+                    )
+                }
+                None => break,
+            }
+        }
+
+        assert_eq!(names, vec!["erin", "dave", "carol", "alice"]);
+    }
+
+    #[sqlx::test]
+    async fn load_users_with_unknown_sort_should_return_400(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let app = create_router().with_state(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/users?sort=password")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn search_users_should_return_ranked_hits(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let id = generate_xid_string();
+
+        sqlx::query("INSERT INTO users (id, name, email) VALUES ($1, $2, $3)")
+            .bind(&id)
+            .bind("Searchable Person")
+            .bind("findme@example.com")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+
+        let app = create_router().with_state(state.clone());
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/users/search?q=search%20pers")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let result: UserSearchResult = serde_json::from_slice(&body).unwrap();
+        assert_eq!(result.users.len(), 1);
+        assert_eq!(result.users[0].user.id, id);
+        assert_eq!(
+            result.users[0].highlight.name,
+            "<mark>Searchable</mark> <mark>Person</mark>"
+        );
+    }
+
+    #[sqlx::test]
+    async fn search_users_without_terms_should_return_400(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let app = create_router().with_state(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/users/search?q=%22%22")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn find_user_should_return_200(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let id = generate_xid_string();
+        let name = format!("user-{}", &id[..8]);
+        let email = format!("{}@example.com", &id[..8]);
+
+        sqlx::query("INSERT INTO users (id, name, email) VALUES ($1, $2, $3)")
+            .bind(&id)
+            .bind(&name)
+            .bind(&email)
+            .execute(&state.pool)
+            .await
+            .unwrap();
+
+        let app = create_router().with_state(state.clone());
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("GET")
+                    .uri(format!("/users/{}", id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let user: User = serde_json::from_value(body).unwrap();
+        assert_eq!(user.id, id);
+        assert_eq!(user.name, name);
+        assert_eq!(user.email, email);
+    }
+
+    #[sqlx::test]
+    async fn create_user_should_return_202(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let app = create_router().with_state(state.clone());
+
+        let user = CreateUserRequest {
+            name: "test-user".to_string(),
+            email: "test@example.com".to_string(),
+        };
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/users")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(serde_json::to_string(&user).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert!(body["id"].as_str().unwrap().len() == 24);
+        assert_eq!(body["status"], "accepted");
+    }
+
+    #[sqlx::test]
+    async fn update_user_should_return_202(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let id = generate_xid_string();
+
+        sqlx::query("INSERT INTO users (id, name, email) VALUES ($1, $2, $3)")
+            .bind(&id)
+            .bind("before")
+            .bind("before@example.com")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+
+        let app = create_router().with_state(state.clone());
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("PATCH")
+                    .uri(format!("/users/{}", id))
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(json!({ "name": "after" }).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["id"], id);
+        assert_eq!(body["status"], "accepted");
+        assert!(body["operation_id"].is_string());
+
+        let user = sqlx::query_as::<_, User>(
+            "SELECT id, name, email, created_at, updated_at FROM users WHERE id = $1",
+        )
+        .bind(&id)
+        .fetch_one(&state.pool)
+        .await
+        .unwrap();
+        assert_eq!(user.name, "after");
+        assert_eq!(user.email, "before@example.com");
+    }
+
+    #[sqlx::test]
+    async fn replace_user_should_return_202(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let id = generate_xid_string();
+
+        sqlx::query("INSERT INTO users (id, name, email) VALUES ($1, $2, $3)")
+            .bind(&id)
+            .bind("before")
+            .bind("before@example.com")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+
+        let replacement = CreateUserRequest {
+            name: "after".to_string(),
+            email: "after@example.com".to_string(),
+        };
+
+        let app = create_router().with_state(state.clone());
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri(format!("/users/{}", id))
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(serde_json::to_string(&replacement).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let user = sqlx::query_as::<_, User>(
+            "SELECT id, name, email, created_at, updated_at FROM users WHERE id = $1",
+        )
+        .bind(&id)
+        .fetch_one(&state.pool)
+        .await
+        .unwrap();
+        assert_eq!(user.name, replacement.name);
+        assert_eq!(user.email, replacement.email);
+    }
+
+    #[sqlx::test]
+    async fn delete_user_should_return_202(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let id = generate_xid_string();
+
+        sqlx::query("INSERT INTO users (id, name, email) VALUES ($1, $2, $3)")
+            .bind(&id)
+            .bind("to-delete")
+            .bind("delete@example.com")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+
+        let app = create_router().with_state(state.clone());
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("DELETE")
+                    .uri(format!("/users/{}", id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let remaining: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE id = $1")
+            .bind(&id)
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+        assert_eq!(remaining, 0);
+    }
+
+    #[sqlx::test]
+    async fn operation_status_should_follow_each_write(pool: SqlitePool) {
+        // Spooled messages stay queued until the test hands them to the writer.
+        let spool = std::env::temp_dir().join(format!("status-{}.jsonl", generate_xid_string()));
+        let state = Arc::new(AppState {
+            publisher: Box::new(crate::sqs::SpoolPublisher::open(&spool).await.unwrap()),
+            ..AppState::new(pool)
+        });
+
+        let request = |method: &str, uri: String, body: Body| {
+            create_router().with_state(state.clone()).oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(body)
+                    .unwrap(),
+            )
+        };
+        let status_of = |operation_id: String| async move {
+            let response = request(
+                "GET",
+                format!("/operations/{}", operation_id),
+                Body::empty(),
+            )
+            .await
+            .unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice::<Value>(&body).unwrap())
+        };
+
+        let response = request(
+            "POST",
+            "/users".to_string(),
+            Body::from(r#"{"name": "status", "email": "status@example.com"}"#),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let location = response.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let created: Value = serde_json::from_slice(&body).unwrap();
+        let id = created["id"].as_str().unwrap().to_string();
+        let create_id = created["operation_id"].as_str().unwrap().to_string();
+        assert_eq!(location, format!("/operations/{}", create_id));
+
+        assert_eq!(
+            status_of(create_id.clone()).await,
+            (
+                StatusCode::OK,
+                json!({ "id": create_id, "status": "pending", "user_id": id, "operation": "create" })
+            )
+        );
+
+        let spooled = std::fs::read_to_string(&spool).unwrap();
+        let event = SqsEvent {
+            records: vec![serde_json::from_str(spooled.trim()).unwrap()],
+        };
+        writer::process_event(&state.pool, &state.lease, &event).await;
+
+        assert_eq!(
+            status_of(create_id.clone()).await,
+            (
+                StatusCode::OK,
+                json!({ "id": create_id, "status": "applied", "user_id": id, "operation": "create" })
+            )
+        );
+
+        // An update in flight is pending on its own, whatever came before it.
+        let response = request(
+            "PATCH",
+            format!("/users/{}", id),
+            Body::from(r#"{"name": "renamed"}"#),
+        )
+        .await
+        .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let updated: Value = serde_json::from_slice(&body).unwrap();
+        let update_id = updated["operation_id"].as_str().unwrap().to_string();
+        assert_ne!(update_id, create_id);
+        assert_eq!(status_of(update_id).await.1["status"], "pending");
+
+        // Only the ids of operations are known, not any recent XID.
+        for unknown in [
+            "does-not-exist".to_string(),
+            id.clone(),
+            generate_xid_string(),
+        ] {
+            let (status, body) = status_of(unknown).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert_eq!(body["code"], "not_found");
+        }
+        std::fs::remove_file(&spool).unwrap();
+    }
+
+    #[sqlx::test]
+    async fn find_missing_user_should_return_404(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let app = create_router().with_state(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/users/does-not-exist")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.headers()["content-type"],
+            "application/problem+json"
+        );
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], 404);
+        assert_eq!(body["code"], "not_found");
+    }
+
+    #[sqlx::test]
+    async fn create_user_with_invalid_body_should_return_422(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let app = create_router().with_state(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/users")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(json!({ "name": "no-email" }).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "unprocessable_entity");
+        assert!(body["detail"].as_str().unwrap().contains("email"));
+    }
+
+    #[sqlx::test]
+    async fn create_user_with_invalid_fields_should_return_field_errors(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let app = create_router().with_state(state.clone());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/users")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(
+                        json!({ "name": "  ", "email": "nope" }).to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "validation_failed");
+        assert_eq!(body["errors"]["name"], json!(["must not be blank"]));
+        assert_eq!(
+            body["errors"]["email"],
+            json!(["must be a valid email address"])
+        );
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[sqlx::test]
+    async fn create_user_with_taken_email_should_return_409(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let operation = QueuedOperation::CreateUser(QueuedUser {
+            id: generate_xid_string(),
+            name: "owner".to_string(),
+            email: "owner@example.com".to_string(),
+        });
+        writer::apply_direct(
+            &state.pool,
+            &state.lease,
+            &generate_xid_string(),
+            &operation,
+        )
+        .await
+        .unwrap();
+
+        let app = create_router().with_state(state.clone());
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/users")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(
+                        json!({ "name": "copycat", "email": " Owner@Example.com " }).to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "conflict");
+        assert_eq!(body["detail"], "email address is already taken");
+    }
+
+    #[sqlx::test]
+    async fn unknown_api_should_be_handled_by_fallback_handler(pool: SqlitePool) {
+        let state = Arc::new(AppState::new(pool));
+        let app = create_router().with_state(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/does-not-exist")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
         let body = response.into_body().collect().await.unwrap().to_bytes();
         let body: Value = serde_json::from_slice(&body).unwrap();
-        assert_eq!(body, json!({ "message": "No route for /does-not-exist" }));
+        assert_eq!(body["status"], 404);
+        assert_eq!(body["code"], "not_found");
     }
 }
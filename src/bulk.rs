@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use crate::{error::ApiError, models::CreateUserRequest};
+
+/// Upper bound on the rows accepted by one `POST /users:bulk` request.
+pub const MAX_BULK_ROWS: usize = 5_000;
+
+pub type RowErrors = BTreeMap<String, Vec<String>>;
+
+/// Parses a bulk upload into one entry per input row. Rows that cannot be
+/// read as a user are kept as row errors so the rest can still be imported.
+pub fn parse_rows(
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<Vec<Result<CreateUserRequest, RowErrors>>, ApiError> {
+    let media_type = content_type
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase());
+
+    let rows = match media_type.as_deref() {
+        Some("application/json") => parse_json(body)?,
+        Some("text/csv") => parse_csv(body)?,
+        _ => {
+            return Err(ApiError::UnsupportedMediaType(
+                "expected application/json or text/csv".to_string(),
+            ))
+        }
+    };
+
+    if rows.len() > MAX_BULK_ROWS {
+        return Err(ApiError::BadRequest(format!(
+            "at most {} rows can be imported at once",
+            MAX_BULK_ROWS
+        )));
+    }
+
+    Ok(rows)
+}
+
+fn parse_json(body: &[u8]) -> Result<Vec<Result<CreateUserRequest, RowErrors>>, ApiError> {
+    let rows: Vec<Value> = serde_json::from_slice(body)
+        .map_err(|e| ApiError::BadRequest(format!("expected a JSON array of users: {}", e)))?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| serde_json::from_value(row).map_err(|e| row_error(e.to_string())))
+        .collect())
+}
+
+/// Expects a header row naming at least the `name` and `email` columns.
+fn parse_csv(body: &[u8]) -> Result<Vec<Result<CreateUserRequest, RowErrors>>, ApiError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(body);
+
+    let headers = reader
+        .headers()
+        .map_err(|e| ApiError::BadRequest(format!("invalid CSV header: {}", e)))?;
+    for column in ["name", "email"] {
+        if !headers.iter().any(|header| header == column) {
+            return Err(ApiError::BadRequest(format!(
+                "CSV header is missing the {} column",
+                column
+            )));
+        }
+    }
+
+    Ok(reader
+        .deserialize()
+        .map(|row| row.map_err(|e| row_error(e.to_string())))
+        .collect())
+}
+
+pub fn row_error(message: impl Into<String>) -> RowErrors {
+    BTreeMap::from([("row".to_string(), vec![message.into()])])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_rows_are_parsed_independently() {
+        let body =
+            b"email,name\nada@example.com,Ada\n\"grace@example.com\",\"Hopper, Grace\"\nbroken\n";
+
+        let rows = parse_rows(Some("text/csv; charset=utf-8"), body).unwrap();
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[1].as_ref().unwrap().name, "Hopper, Grace");
+        assert!(rows[2].as_ref().unwrap_err().contains_key("row"));
+    }
+
+    #[test]
+    fn json_rows_with_missing_fields_become_row_errors() {
+        let body = br#"[{"name": "Ada", "email": "ada@example.com"}, {"name": "Bob"}]"#;
+
+        let rows = parse_rows(Some("application/json"), body).unwrap();
+
+        assert!(rows[0].is_ok());
+        assert!(rows[1].is_err());
+        assert!(matches!(
+            parse_rows(Some("text/plain"), body),
+            Err(ApiError::UnsupportedMediaType(_))
+        ));
+    }
+}
@@ -0,0 +1,329 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::{
+    db::{
+        DatabaseAccess, DatabaseSettings, DEFAULT_BOOKKEEPING_DATABASE_URL, DEFAULT_DATABASE_PATH,
+        DEFAULT_DATABASE_URL,
+    },
+    lease::LeaseSettings,
+    poller::PollerSettings,
+    sqs::{PublisherKind, SqsSettings, DEFAULT_SPOOL_PATH},
+};
+
+/// Every setting, by the name of its environment variable. A TOML file uses
+/// the same names in lower case, optionally split into tables at an
+/// underscore: `SQS_QUEUE_URL` can be `queue_url` in `[sqs]`.
+pub const KEYS: &[&str] = &[
+    "PORT",
+    "API_MODE",
+    "API_LOCAL_WRITES",
+    "WRITER_MODE",
+    "DATABASE_URL",
+    "DATABASE_PATH",
+    "BOOKKEEPING_DATABASE_URL",
+    "DATABASE_MAX_CONNECTIONS",
+    "DATABASE_MIN_CONNECTIONS",
+    "DATABASE_ACQUIRE_TIMEOUT_MS",
+    "DATABASE_IDLE_TIMEOUT_SECONDS",
+    "SQLITE_JOURNAL_MODE",
+    "SQLITE_SYNCHRONOUS",
+    "SQLITE_BUSY_TIMEOUT_MS",
+    "SQLITE_CACHE_SIZE",
+    "SQLITE_MMAP_SIZE",
+    "SQLITE_LOCKING_MODE",
+    "SQLITE_FOREIGN_KEYS",
+    "PUBLISHER",
+    "PUBLISHER_SPOOL_PATH",
+    "SQS_QUEUE_URL",
+    "SQS_ENDPOINT_URL",
+    "SQS_MAX_ATTEMPTS",
+    "SQS_CONNECT_TIMEOUT_MS",
+    "SQS_OPERATION_TIMEOUT_MS",
+    "SQS_WAIT_TIME_SECONDS",
+    "SQS_VISIBILITY_TIMEOUT_SECONDS",
+    "SQS_MAX_MESSAGES",
+    "WRITER_LEASE_TTL_SECONDS",
+    "WRITER_LEASE_LOCK_FILE",
+];
+
+/// How the `api` binary receives requests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApiMode {
+    /// Serves the router on `PORT`, also behind the Lambda Web Adapter.
+    Http,
+    /// Reads invocations from the Lambda runtime API.
+    Lambda,
+}
+
+/// How the `writer` binary receives SQS messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WriterMode {
+    /// Events posted by the Lambda Web Adapter to `POST /events`.
+    Http,
+    /// Long-polls `SQS_QUEUE_URL` itself.
+    Poll,
+    /// Reads invocations from the Lambda runtime API.
+    Lambda,
+}
+
+/// Configuration of both binaries, loaded once at startup. Each setting is
+/// taken from the environment (including `.env`), then from the TOML file
+/// named by `CONFIG_FILE`, then from its default.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// TOML file the settings were read from, if any.
+    pub file: Option<PathBuf>,
+    /// `PORT`: where the HTTP modes listen; each binary has its own default.
+    pub port: Option<u16>,
+    pub api_mode: ApiMode,
+    /// `API_LOCAL_WRITES`: lets the API migrate and write the database
+    /// itself, for local development.
+    pub api_local_writes: bool,
+    pub writer_mode: WriterMode,
+    pub database_url: String,
+    pub database_path: String,
+    /// `BOOKKEEPING_DATABASE_URL`: where a read-only API records idempotency
+    /// keys and issued operations, shared by all its instances.
+    pub bookkeeping_database_url: String,
+    pub database: DatabaseSettings,
+    pub publisher: PublisherKind,
+    pub spool_path: PathBuf,
+    pub sqs: Option<SqsSettings>,
+    pub poller: PollerSettings,
+    pub lease: LeaseSettings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::from_lookup(|_| None).expect("default configuration is valid")
+    }
+}
+
+impl Config {
+    /// Reads `.env`, then the environment and the optional `CONFIG_FILE`.
+    pub fn load() -> Result<Self, String> {
+        dotenv::dotenv().ok();
+
+        let file = std::env::var("CONFIG_FILE").ok().map(PathBuf::from);
+        let values = match &file {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+                parse_toml(&contents).map_err(|e| format!("{}: {}", path.display(), e))?
+            }
+            None => HashMap::new(),
+        };
+
+        let config = Config::from_lookup(|key| {
+            std::env::var(key).ok().or_else(|| values.get(key).cloned())
+        })?;
+
+        Ok(Config { file, ..config })
+    }
+
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let port = lookup("PORT")
+            .map(|value| {
+                value
+                    .parse::<u16>()
+                    .map_err(|_| format!("PORT must be a port number, got {:?}", value))
+            })
+            .transpose()?;
+
+        let api_mode = match lookup("API_MODE").as_deref() {
+            Some("http") | None => ApiMode::Http,
+            Some("lambda") => ApiMode::Lambda,
+            Some(other) => return Err(format!("API_MODE must be http or lambda, got {:?}", other)),
+        };
+
+        let writer_mode = match lookup("WRITER_MODE").as_deref() {
+            Some("http") | None => WriterMode::Http,
+            Some("poll") => WriterMode::Poll,
+            Some("lambda") => WriterMode::Lambda,
+            Some(other) => {
+                return Err(format!(
+                    "WRITER_MODE must be http, poll or lambda, got {:?}",
+                    other
+                ))
+            }
+        };
+
+        let api_local_writes = parse_bool(&lookup, "API_LOCAL_WRITES")?.unwrap_or(false);
+
+        let sqs = SqsSettings::from_lookup(&lookup)?;
+        let publisher = match lookup("PUBLISHER") {
+            Some(kind) => kind.parse()?,
+            None if sqs.is_some() => PublisherKind::Sqs,
+            None => PublisherKind::Direct,
+        };
+
+        let config = Config {
+            file: None,
+            port,
+            api_mode,
+            api_local_writes,
+            writer_mode,
+            database_url: lookup("DATABASE_URL")
+                .unwrap_or_else(|| DEFAULT_DATABASE_URL.to_string()),
+            database_path: lookup("DATABASE_PATH")
+                .unwrap_or_else(|| DEFAULT_DATABASE_PATH.to_string()),
+            bookkeeping_database_url: lookup("BOOKKEEPING_DATABASE_URL")
+                .unwrap_or_else(|| DEFAULT_BOOKKEEPING_DATABASE_URL.to_string()),
+            database: DatabaseSettings::from_lookup(&lookup)?,
+            publisher,
+            spool_path: lookup("PUBLISHER_SPOOL_PATH")
+                .unwrap_or_else(|| DEFAULT_SPOOL_PATH.to_string())
+                .into(),
+            sqs,
+            poller: PollerSettings::from_lookup(&lookup)?,
+            lease: LeaseSettings::from_lookup(&lookup)?,
+        };
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.publisher == PublisherKind::Sqs && self.sqs.is_none() {
+            return Err("PUBLISHER=sqs requires SQS_QUEUE_URL".to_string());
+        }
+        if self.writer_mode == WriterMode::Poll && self.sqs.is_none() {
+            return Err("WRITER_MODE=poll requires SQS_QUEUE_URL".to_string());
+        }
+        if !cfg!(feature = "lambda-runtime")
+            && (self.api_mode == ApiMode::Lambda || self.writer_mode == WriterMode::Lambda)
+        {
+            return Err(
+                "API_MODE=lambda and WRITER_MODE=lambda need a build with the lambda-runtime feature"
+                    .to_string(),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The API only reads, unless it is allowed to write for local
+    /// development.
+    pub fn api_access(&self) -> DatabaseAccess {
+        if self.api_local_writes {
+            DatabaseAccess::ReadWrite
+        } else {
+            DatabaseAccess::ReadOnly
+        }
+    }
+}
+
+/// Reads a boolean setting, written as `true`/`false` or `1`/`0`.
+pub fn parse_bool(
+    lookup: impl Fn(&str) -> Option<String>,
+    key: &str,
+) -> Result<Option<bool>, String> {
+    match lookup(key).as_deref() {
+        Some("true") | Some("1") => Ok(Some(true)),
+        Some("false") | Some("0") => Ok(Some(false)),
+        None => Ok(None),
+        Some(other) => Err(format!("{} must be true or false, got {:?}", key, other)),
+    }
+}
+
+/// Flattens a TOML document into settings named like their environment
+/// variables, rejecting names that are not in [`KEYS`].
+pub fn parse_toml(contents: &str) -> Result<HashMap<String, String>, String> {
+    let table: toml::Table = contents.parse().map_err(|e| format!("{}", e))?;
+    let mut values = HashMap::new();
+    flatten("", &table, &mut values)?;
+
+    Ok(values)
+}
+
+fn flatten(
+    prefix: &str,
+    table: &toml::Table,
+    values: &mut HashMap<String, String>,
+) -> Result<(), String> {
+    for (key, value) in table {
+        let name = match prefix {
+            "" => key.to_uppercase(),
+            prefix => format!("{}_{}", prefix, key.to_uppercase()),
+        };
+
+        let value = match value {
+            toml::Value::Table(table) => {
+                flatten(&name, table, values)?;
+                continue;
+            }
+            toml::Value::String(value) => value.clone(),
+            toml::Value::Integer(value) => value.to_string(),
+            toml::Value::Boolean(value) => value.to_string(),
+            _ => {
+                return Err(format!(
+                    "{} must be a string, an integer or a boolean",
+                    name
+                ))
+            }
+        };
+
+        if !KEYS.contains(&name.as_str()) {
+            return Err(format!("unknown setting {}", name));
+        }
+        values.insert(name, value);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let file = parse_toml(
+            r#"
+            port = 8080
+            api_mode = "http"
+
+            [sqs]
+            queue_url = "http://localhost:9324/queue/users"
+            max_messages = 5
+
+            [writer_lease]
+            ttl_seconds = 10
+            "#,
+        )
+        .unwrap();
+        let env: HashMap<String, String> = [("PORT", "9000"), ("WRITER_MODE", "poll")]
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        let config =
+            Config::from_lookup(|key| env.get(key).or_else(|| file.get(key)).cloned()).unwrap();
+
+        assert_eq!(config.port, Some(9000));
+        assert_eq!(config.writer_mode, WriterMode::Poll);
+        assert_eq!(config.publisher, PublisherKind::Sqs);
+        assert_eq!(config.poller.max_messages, 5);
+        assert_eq!(config.lease.ttl, Duration::from_secs(10));
+        assert_eq!(config.database_url, DEFAULT_DATABASE_URL);
+        assert_eq!(config.api_access(), DatabaseAccess::ReadOnly);
+    }
+
+    #[test]
+    fn invalid_settings_are_reported_by_name() {
+        assert_eq!(
+            parse_toml("[sqs]\nqueue = \"q\"").unwrap_err(),
+            "unknown setting SQS_QUEUE"
+        );
+
+        let error = |key: &'static str, value: &'static str| {
+            Config::from_lookup(|k| (k == key).then(|| value.to_string())).unwrap_err()
+        };
+        assert!(error("PORT", "http").contains("PORT"));
+        assert!(error("PUBLISHER", "sqs").contains("SQS_QUEUE_URL"));
+        assert!(error("WRITER_MODE", "poll").contains("SQS_QUEUE_URL"));
+        assert!(error("SQLITE_SYNCHRONOUS", "sometimes").contains("SQLITE_SYNCHRONOUS"));
+    }
+}
@@ -1,50 +1,529 @@
-use std::{fs, sync::Arc};
+use std::{
+    fs,
+    str::FromStr,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
 use tracing::log::LevelFilter;
 
 use sqlx::{
-    sqlite::{SqliteConnectOptions, SqlitePool},
+    sqlite::{
+        SqliteConnectOptions, SqliteJournalMode, SqliteLockingMode, SqlitePool, SqlitePoolOptions,
+        SqliteSynchronous,
+    },
     ConnectOptions,
 };
 
-use crate::models::AppState;
+use crate::{
+    config::{self, Config},
+    lease::{LeaseError, WriterLease},
+    models::AppState,
+    sqs,
+    validation::normalize_email,
+};
 
 pub const DEFAULT_DATABASE_URL: &str = "sqlite:users.db";
 pub const DEFAULT_DATABASE_PATH: &str = "./users.db";
+pub const DEFAULT_BOOKKEEPING_DATABASE_URL: &str = "sqlite:api-bookkeeping.db";
 
-pub async fn bootstrap() -> Arc<AppState> {
-    dotenv::dotenv().ok();
+/// How a binary uses the shared database.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DatabaseAccess {
+    /// Runs migrations and applies writes: the writer, or the API during
+    /// local development.
+    ReadWrite,
+    /// Opens the database with `mode=ro` and `query_only`, and only checks
+    /// that the writer has migrated it.
+    ReadOnly,
+}
 
-    let database_url =
-        std::env::var("DATABASE_URL").unwrap_or_else(|_| DEFAULT_DATABASE_URL.to_string());
-    let database_path =
-        std::env::var("DATABASE_PATH").unwrap_or_else(|_| DEFAULT_DATABASE_PATH.to_string());
+pub const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_MAX_CONNECTIONS: u32 = 10;
+pub const DEFAULT_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
 
-    let file_metadata = fs::metadata(&database_path);
-    if file_metadata.is_err() {
-        let _ = fs::File::create(&database_path);
+/// Pragmas applied to every pooled connection and the pool limits, part of
+/// [`Config`]:
+///
+/// - `SQLITE_JOURNAL_MODE`: `wal`, or one of the rollback journals `delete`,
+///   `truncate` and `persist` (also `memory` and `off`). WAL needs shared
+///   memory that NFS does not provide, unless the locking mode is
+///   `exclusive`. Only set when writing, as the mode is stored in the file.
+/// - `SQLITE_SYNCHRONOUS`: `off`, `normal`, `full` or `extra`.
+/// - `SQLITE_BUSY_TIMEOUT_MS`: how long to wait for a lock held elsewhere.
+/// - `SQLITE_CACHE_SIZE`: pages, or KiB when negative, as `PRAGMA cache_size`.
+/// - `SQLITE_MMAP_SIZE`: bytes of the file to memory-map, 0 to disable.
+/// - `SQLITE_LOCKING_MODE`: `normal`, or `exclusive` to keep the file locked
+///   for the life of the connection; only set when writing, with a single
+///   connection.
+/// - `SQLITE_FOREIGN_KEYS`: `true` or `false`.
+/// - `DATABASE_MAX_CONNECTIONS`, `DATABASE_MIN_CONNECTIONS`: pool size.
+/// - `DATABASE_ACQUIRE_TIMEOUT_MS`: how long to wait for a free connection.
+/// - `DATABASE_IDLE_TIMEOUT_SECONDS`: when idle connections above the
+///   minimum are closed, 0 to keep them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatabaseSettings {
+    pub journal_mode: SqliteJournalMode,
+    pub synchronous: SqliteSynchronous,
+    pub busy_timeout: Duration,
+    pub cache_size: Option<i64>,
+    pub mmap_size: Option<u64>,
+    pub locking_mode: SqliteLockingMode,
+    pub foreign_keys: bool,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout: Duration,
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for DatabaseSettings {
+    fn default() -> Self {
+        DatabaseSettings {
+            journal_mode: SqliteJournalMode::Wal,
+            synchronous: SqliteSynchronous::Full,
+            busy_timeout: DEFAULT_BUSY_TIMEOUT,
+            cache_size: None,
+            mmap_size: None,
+            locking_mode: SqliteLockingMode::Normal,
+            foreign_keys: true,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            min_connections: 0,
+            acquire_timeout: DEFAULT_ACQUIRE_TIMEOUT,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+        }
     }
+}
 
-    let connection_options: SqliteConnectOptions = database_url.parse().unwrap();
-    let pool = SqlitePool::connect_with(connection_options.log_statements(LevelFilter::Off))
+impl DatabaseSettings {
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        fn parse<T: FromStr>(
+            key: &str,
+            value: Option<String>,
+            expected: &str,
+        ) -> Result<Option<T>, String> {
+            value
+                .map(|value| {
+                    value
+                        .parse::<T>()
+                        .map_err(|_| format!("{} must be {}, got {:?}", key, expected, value))
+                })
+                .transpose()
+        }
+        let defaults = DatabaseSettings::default();
+
+        let foreign_keys =
+            config::parse_bool(&lookup, "SQLITE_FOREIGN_KEYS")?.unwrap_or(defaults.foreign_keys);
+
+        let settings = DatabaseSettings {
+            journal_mode: parse(
+                "SQLITE_JOURNAL_MODE",
+                lookup("SQLITE_JOURNAL_MODE"),
+                "one of wal, delete, truncate, persist, memory or off",
+            )?
+            .unwrap_or(defaults.journal_mode),
+            synchronous: parse(
+                "SQLITE_SYNCHRONOUS",
+                lookup("SQLITE_SYNCHRONOUS"),
+                "one of off, normal, full or extra",
+            )?
+            .unwrap_or(defaults.synchronous),
+            busy_timeout: parse::<u64>(
+                "SQLITE_BUSY_TIMEOUT_MS",
+                lookup("SQLITE_BUSY_TIMEOUT_MS"),
+                "a number of milliseconds",
+            )?
+            .map(Duration::from_millis)
+            .unwrap_or(defaults.busy_timeout),
+            cache_size: parse(
+                "SQLITE_CACHE_SIZE",
+                lookup("SQLITE_CACHE_SIZE"),
+                "an integer",
+            )?,
+            mmap_size: parse(
+                "SQLITE_MMAP_SIZE",
+                lookup("SQLITE_MMAP_SIZE"),
+                "a number of bytes",
+            )?,
+            locking_mode: parse(
+                "SQLITE_LOCKING_MODE",
+                lookup("SQLITE_LOCKING_MODE"),
+                "normal or exclusive",
+            )?
+            .unwrap_or(defaults.locking_mode),
+            foreign_keys,
+            max_connections: parse(
+                "DATABASE_MAX_CONNECTIONS",
+                lookup("DATABASE_MAX_CONNECTIONS"),
+                "a positive integer",
+            )?
+            .unwrap_or(defaults.max_connections),
+            min_connections: parse(
+                "DATABASE_MIN_CONNECTIONS",
+                lookup("DATABASE_MIN_CONNECTIONS"),
+                "an integer",
+            )?
+            .unwrap_or(defaults.min_connections),
+            acquire_timeout: parse::<u64>(
+                "DATABASE_ACQUIRE_TIMEOUT_MS",
+                lookup("DATABASE_ACQUIRE_TIMEOUT_MS"),
+                "a number of milliseconds",
+            )?
+            .map(Duration::from_millis)
+            .unwrap_or(defaults.acquire_timeout),
+            idle_timeout: match parse::<u64>(
+                "DATABASE_IDLE_TIMEOUT_SECONDS",
+                lookup("DATABASE_IDLE_TIMEOUT_SECONDS"),
+                "a number of seconds",
+            )? {
+                Some(0) => None,
+                Some(seconds) => Some(Duration::from_secs(seconds)),
+                None => defaults.idle_timeout,
+            },
+        };
+
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.max_connections == 0 {
+            return Err("DATABASE_MAX_CONNECTIONS must be at least 1".to_string());
+        }
+        if self.min_connections > self.max_connections {
+            return Err(format!(
+                "DATABASE_MIN_CONNECTIONS ({}) must not exceed DATABASE_MAX_CONNECTIONS ({})",
+                self.min_connections, self.max_connections
+            ));
+        }
+        if self.acquire_timeout.is_zero() {
+            return Err("DATABASE_ACQUIRE_TIMEOUT_MS must be at least 1".to_string());
+        }
+        // Each connection in exclusive mode keeps its lock, so a second one
+        // would wait for the busy timeout on every write.
+        if self.locking_mode == SqliteLockingMode::Exclusive && self.max_connections != 1 {
+            return Err(
+                "SQLITE_LOCKING_MODE=exclusive requires DATABASE_MAX_CONNECTIONS=1".to_string(),
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn connect_options(
+        &self,
+        database_url: &str,
+        access: DatabaseAccess,
+    ) -> SqliteConnectOptions {
+        let connection_options: SqliteConnectOptions = database_url.parse().unwrap();
+        let mut connection_options = connection_options
+            .synchronous(self.synchronous)
+            .busy_timeout(self.busy_timeout)
+            .foreign_keys(self.foreign_keys);
+
+        if let Some(cache_size) = self.cache_size {
+            connection_options = connection_options.pragma("cache_size", cache_size.to_string());
+        }
+        if let Some(mmap_size) = self.mmap_size {
+            connection_options = connection_options.pragma("mmap_size", mmap_size.to_string());
+        }
+
+        // The journal and locking modes change the file for every other
+        // process, so readers leave them to the writer.
+        let connection_options = match access {
+            DatabaseAccess::ReadWrite => connection_options
+                .locking_mode(self.locking_mode)
+                .journal_mode(self.journal_mode),
+            DatabaseAccess::ReadOnly => connection_options
+                .read_only(true)
+                .pragma("query_only", "ON"),
+        };
+
+        connection_options.log_statements(LevelFilter::Off)
+    }
+
+    pub fn pool_options(&self) -> SqlitePoolOptions {
+        SqlitePoolOptions::new()
+            .max_connections(self.max_connections)
+            .min_connections(self.min_connections)
+            .acquire_timeout(self.acquire_timeout)
+            .idle_timeout(self.idle_timeout)
+    }
+
+    pub async fn connect(
+        &self,
+        database_url: &str,
+        access: DatabaseAccess,
+    ) -> Result<SqlitePool, sqlx::Error> {
+        self.pool_options()
+            .connect_with(self.connect_options(database_url, access))
+            .await
+    }
+
+    /// Opens connections only when they are used, so that a read-only pool
+    /// can be created before the writer has created the database file.
+    pub fn connect_lazy(&self, database_url: &str, access: DatabaseAccess) -> SqlitePool {
+        self.pool_options()
+            .connect_lazy_with(self.connect_options(database_url, access))
+    }
+
+    fn log(&self, access: DatabaseAccess) {
+        match access {
+            DatabaseAccess::ReadWrite => tracing::info!(
+                "SQLite journal_mode={:?} locking_mode={:?}",
+                self.journal_mode,
+                self.locking_mode
+            ),
+            DatabaseAccess::ReadOnly => tracing::info!("SQLite opened read-only"),
+        }
+        tracing::info!(
+            "SQLite synchronous={:?} busy_timeout={:?} cache_size={:?} mmap_size={:?} foreign_keys={}",
+            self.synchronous,
+            self.busy_timeout,
+            self.cache_size,
+            self.mmap_size,
+            self.foreign_keys
+        );
+        tracing::info!(
+            "Pool max_connections={} min_connections={} acquire_timeout={:?} idle_timeout={:?}",
+            self.max_connections,
+            self.min_connections,
+            self.acquire_timeout,
+            self.idle_timeout
+        );
+    }
+}
+
+pub async fn bootstrap(config: Config, access: DatabaseAccess) -> Arc<AppState> {
+    tracing_subscriber::fmt::init();
+
+    if let Some(file) = &config.file {
+        tracing::info!("Configuration read from {}", file.display());
+    }
+
+    if access == DatabaseAccess::ReadWrite {
+        let file_metadata = fs::metadata(&config.database_path);
+        if file_metadata.is_err() {
+            let _ = fs::File::create(&config.database_path);
+        }
+    }
+
+    config.database.log(access);
+
+    let pool = match access {
+        DatabaseAccess::ReadWrite => config
+            .database
+            .connect(&config.database_url, access)
+            .await
+            .expect("Failed to connect to database"),
+        DatabaseAccess::ReadOnly => config.database.connect_lazy(&config.database_url, access),
+    };
+
+    let lease = Arc::new(WriterLease::new(config.lease.clone()));
+
+    let schema_ready = match access {
+        DatabaseAccess::ReadWrite => {
+            sqlx::migrate!().run(&pool).await.unwrap();
+            match normalize_pending_emails(&pool, &lease).await {
+                Ok(0) => {}
+                Ok(count) => tracing::info!("Normalized {} stored email addresses", count),
+                Err(e @ (LeaseError::Database(_) | LeaseError::Io(_))) => {
+                    panic!("Failed to normalize stored email addresses: {}", e)
+                }
+                Err(e) => tracing::warn!(
+                    "Stored email addresses are normalized on a later start: {}",
+                    e
+                ),
+            }
+            report_duplicate_emails(&pool).await;
+            true
+        }
+        DatabaseAccess::ReadOnly => match verify_schema(&pool).await {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!("Answering 503 until the database is migrated: {}", e);
+                false
+            }
+        },
+    };
+
+    let bookkeeping = match access {
+        DatabaseAccess::ReadWrite => pool.clone(),
+        DatabaseAccess::ReadOnly => connect_bookkeeping(&config)
+            .await
+            .expect("Failed to open the bookkeeping database"),
+    };
+
+    let publisher = sqs::publisher_from_config(&config, &pool, &lease, access)
         .await
-        .expect("Failed to connect to database");
+        .expect("Invalid publisher configuration");
 
-    sqlx::migrate!().run(&pool).await.unwrap();
+    Arc::new(AppState {
+        pool,
+        publisher,
+        lease,
+        bookkeeping,
+        schema_ready: AtomicBool::new(schema_ready),
+        config,
+    })
+}
 
-    let _ = sqlx::query("PRAGMA journal_mode = WAL;")
-        .execute(&pool)
-        .await;
+/// Opens the database where a read-only API records idempotency keys and the
+/// operations it issued, so that every instance answers retries and status
+/// requests the same way. It is created and migrated by the API itself, with
+/// the schema of the shared database of which only `idempotency_keys` and
+/// `operations` are used.
+pub async fn connect_bookkeeping(config: &Config) -> Result<SqlitePool, sqlx::Error> {
+    let options = config
+        .database
+        .connect_options(&config.bookkeeping_database_url, DatabaseAccess::ReadWrite)
+        .create_if_missing(true);
+    let pool = config.database.pool_options().connect_with(options).await?;
+    sqlx::migrate!().run(&pool).await?;
 
-    let _ = sqlx::query("PRAGMA busy_timeout = 5000;")
-        .execute(&pool)
-        .await;
+    Ok(pool)
+}
 
-    Arc::new(AppState { pool })
+/// Checks that the database has exactly the migrations this build knows
+/// about, for binaries that leave migrating to the writer. A migration that is
+/// missing, was applied from a different file, or is unknown to this build
+/// fails the check, since later migrations may rename or rebuild tables.
+pub async fn verify_schema(pool: &SqlitePool) -> Result<(), String> {
+    let applied: Vec<(i64, Vec<u8>)> = sqlx::query_as(
+        "SELECT version, checksum FROM _sqlx_migrations WHERE success = 1 ORDER BY version",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("failed to read the schema version: {}", e))?;
+
+    let migrator = sqlx::migrate!();
+    for migration in migrator
+        .iter()
+        .filter(|migration| migration.migration_type.is_up_migration())
+    {
+        match applied
+            .iter()
+            .find(|(version, _)| *version == migration.version)
+        {
+            None => {
+                return Err(format!(
+                    "migration {} is not applied; run the writer to migrate the database",
+                    migration.version
+                ))
+            }
+            Some((_, checksum)) if *checksum != *migration.checksum => {
+                return Err(format!(
+                    "migration {} was applied from a different file than this build has",
+                    migration.version
+                ))
+            }
+            Some(_) => {}
+        }
+    }
+
+    match applied.iter().find(|(version, _)| {
+        migrator
+            .iter()
+            .all(|migration| migration.version != *version)
+    }) {
+        Some((version, _)) => Err(format!(
+            "migration {} is unknown to this build; deploy the build that applied it",
+            version
+        )),
+        None => Ok(()),
+    }
 }
 
-pub fn set_default_env_var(key: &str, value: &str) {
-    if std::env::var(key).is_err() {
-        std::env::set_var(key, value);
+/// The migration that added `email_normalized` could only use SQLite's
+/// `lower(trim(...))`, which agrees with [`normalize_email`] on printable
+/// ASCII alone, so it queued the rows with any other character in
+/// `pending_email_normalization`. They are normalized here under the writer
+/// lease and removed from the queue; when two of them end up equal only the
+/// oldest keeps the address. Returns the number of rows changed, and does
+/// nothing once the queue is empty.
+pub async fn normalize_pending_emails(
+    pool: &SqlitePool,
+    lease: &WriterLease,
+) -> Result<u64, LeaseError> {
+    let pending: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM pending_email_normalization")
+        .fetch_one(pool)
+        .await?;
+    if pending == 0 {
+        return Ok(0);
+    }
+
+    let mut tx = pool.begin().await?;
+    let guard = lease.acquire(&mut tx).await?;
+    let rows: Vec<(String, String, Option<String>, String)> = sqlx::query_as(
+        "SELECT id, email, email_normalized, CAST(created_at AS TEXT) FROM users
+        WHERE id IN (SELECT user_id FROM pending_email_normalization)
+        ORDER BY created_at, id",
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut changed = 0;
+    for (id, email, stored, created_at) in rows {
+        let normalized = normalize_email(&email);
+
+        let older: Option<String> = sqlx::query_scalar(
+            "SELECT id FROM users
+            WHERE email_normalized = $1 AND id <> $2 AND (CAST(created_at AS TEXT), id) < ($3, $2)",
+        )
+        .bind(&normalized)
+        .bind(&id)
+        .bind(&created_at)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let normalized = match older {
+            Some(_) => None,
+            None => {
+                changed += sqlx::query(
+                    "UPDATE users SET email_normalized = NULL WHERE email_normalized = $1 AND id <> $2",
+                )
+                .bind(&normalized)
+                .bind(&id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+                Some(normalized)
+            }
+        };
+
+        if normalized != stored {
+            sqlx::query("UPDATE users SET email_normalized = $1 WHERE id = $2")
+                .bind(&normalized)
+                .bind(&id)
+                .execute(&mut *tx)
+                .await?;
+            changed += 1;
+        }
+    }
+
+    sqlx::query("DELETE FROM pending_email_normalization")
+        .execute(&mut *tx)
+        .await?;
+    guard.commit(tx).await?;
+
+    Ok(changed)
+}
+
+/// Users created before emails were unique may share an address with an
+/// older account. Those rows have no normalized email and are only reported.
+async fn report_duplicate_emails(pool: &SqlitePool) {
+    let duplicates: Result<i64, _> =
+        sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE email_normalized IS NULL")
+            .fetch_one(pool)
+            .await;
+
+    match duplicates {
+        Ok(0) => {}
+        Ok(count) => tracing::warn!(
+            "{} users share an email address with an older account and need to be merged",
+            count
+        ),
+        Err(e) => tracing::warn!("Failed to count duplicate emails: {}", e),
     }
 }
 
@@ -60,6 +539,15 @@ pub async fn run_migrations(pool: &SqlitePool) {
 }
 
 pub async fn shutdown_signal(state: Arc<AppState>) {
+    wait_for_signal().await;
+    state.lease.release(&state.pool).await;
+    state.pool.close().await;
+
+    println!("signal received, starting graceful shutdown");
+}
+
+/// Resolves on Ctrl+C or SIGTERM.
+pub async fn wait_for_signal() {
     let ctrl_c = async {
         tokio::signal::ctrl_c()
             .await
@@ -80,13 +568,214 @@ pub async fn shutdown_signal(state: Arc<AppState>) {
     tokio::select! {
         _ = ctrl_c => {
             println!("Closing all remaining connections after CTRL+C");
-            state.pool.close().await;
         },
         _ = terminate => {
             println!("Closing all remaining connections after SIGTERM");
-            state.pool.close().await;
         },
     }
+}
 
-    println!("signal received, starting graceful shutdown");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::ApiError, id::generate_xid_string};
+
+    #[sqlx::test]
+    async fn schema_must_match_the_migrations_of_this_build(pool: SqlitePool) {
+        verify_schema(&pool).await.unwrap();
+
+        let run = |sql: &'static str| sqlx::query(sql).execute(&pool);
+        run(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+            VALUES (99990101000000, 'from a newer build', 1, x'00', 0)",
+        )
+        .await
+        .unwrap();
+        assert!(verify_schema(&pool)
+            .await
+            .unwrap_err()
+            .contains("99990101000000"));
+
+        run("DELETE FROM _sqlx_migrations WHERE version = 99990101000000")
+            .await
+            .unwrap();
+        run("UPDATE _sqlx_migrations SET checksum = x'00' WHERE version = (SELECT MAX(version) FROM _sqlx_migrations)")
+            .await
+            .unwrap();
+        assert!(verify_schema(&pool).await.is_err());
+
+        run("DELETE FROM _sqlx_migrations WHERE version = (SELECT MAX(version) FROM _sqlx_migrations)")
+            .await
+            .unwrap();
+        assert!(verify_schema(&pool).await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn pending_emails_are_normalized_like_new_ones(pool: SqlitePool) {
+        // What the migration's lower(trim(...)) left behind: non-ASCII
+        // letters keep their case and decomposed accents stay decomposed.
+        for (id, email, normalized, created_at) in [
+            (
+                "n1",
+                "ÉVA@example.com",
+                "Éva@example.com",
+                "2024-01-01 00:00:00",
+            ),
+            (
+                "n2",
+                "e\u{301}va@example.com",
+                "e\u{301}va@example.com",
+                "2024-01-02 00:00:00",
+            ),
+            (
+                "n3",
+                "Zoë@example.com",
+                "zoë@example.com",
+                "2024-01-03 00:00:00",
+            ),
+            (
+                "n4",
+                "ada@example.com",
+                "ada@example.com",
+                "2024-01-04 00:00:00",
+            ),
+        ] {
+            sqlx::query(
+                "INSERT INTO users (id, name, email, email_normalized, created_at) VALUES ($1, 'n', $2, $3, $4)",
+            )
+            .bind(id)
+            .bind(email)
+            .bind(normalized)
+            .bind(created_at)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        // The migration queues the rows with characters outside printable
+        // ASCII.
+        sqlx::query(
+            "INSERT INTO pending_email_normalization (user_id)
+            SELECT id FROM users WHERE email GLOB '*[^ -~]*'",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let lease = WriterLease::new(Default::default());
+        assert_eq!(normalize_pending_emails(&pool, &lease).await.unwrap(), 2);
+        assert_eq!(normalize_pending_emails(&pool, &lease).await.unwrap(), 0);
+
+        let pending: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM pending_email_normalization")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(pending, 0);
+
+        let rows: Vec<(String, Option<String>)> =
+            sqlx::query_as("SELECT id, email_normalized FROM users ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("n1".to_string(), Some("éva@example.com".to_string())),
+                ("n2".to_string(), None),
+                ("n3".to_string(), Some("zoë@example.com".to_string())),
+                ("n4".to_string(), Some("ada@example.com".to_string())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn read_only_pool_refuses_writes() {
+        let path = std::env::temp_dir().join(format!("ro-{}.db", generate_xid_string()));
+        let database_url = format!("sqlite:{}?mode=rwc", path.display());
+
+        let settings = DatabaseSettings::default();
+        // The API may start before the writer has created the file.
+        let reader = settings.connect_lazy(&database_url, DatabaseAccess::ReadOnly);
+        assert!(verify_schema(&reader).await.is_err());
+
+        let writer = settings
+            .connect(&database_url, DatabaseAccess::ReadWrite)
+            .await
+            .unwrap();
+        run_migrations(&writer).await;
+        writer.close().await;
+
+        verify_schema(&reader).await.unwrap();
+
+        let err: ApiError =
+            sqlx::query("INSERT INTO users (id, name, email) VALUES ('ro', 'a', 'a@example.com')")
+                .execute(&reader)
+                .await
+                .unwrap_err()
+                .into();
+        assert_eq!(err.code(), "read_only");
+
+        reader.close().await;
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn settings_are_validated() {
+        let settings = DatabaseSettings::from_lookup(|_| None).unwrap();
+        assert_eq!(settings, DatabaseSettings::default());
+
+        let settings = DatabaseSettings::from_lookup(|key| match key {
+            "SQLITE_JOURNAL_MODE" => Some("TRUNCATE".to_string()),
+            "SQLITE_LOCKING_MODE" => Some("exclusive".to_string()),
+            "DATABASE_MAX_CONNECTIONS" => Some("1".to_string()),
+            "DATABASE_IDLE_TIMEOUT_SECONDS" => Some("0".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(settings.journal_mode, SqliteJournalMode::Truncate);
+        assert_eq!(settings.idle_timeout, None);
+
+        let invalid = |key: &'static str, value: &'static str| {
+            DatabaseSettings::from_lookup(|k| (k == key).then(|| value.to_string())).is_err()
+        };
+        assert!(invalid("SQLITE_JOURNAL_MODE", "wall"));
+        assert!(invalid("SQLITE_LOCKING_MODE", "exclusive"));
+        assert!(invalid("DATABASE_MIN_CONNECTIONS", "11"));
+        assert!(invalid("SQLITE_BUSY_TIMEOUT_MS", "-1"));
+    }
+
+    #[tokio::test]
+    async fn pragmas_apply_to_every_connection() {
+        let path = std::env::temp_dir().join(format!("pragmas-{}.db", generate_xid_string()));
+        let database_url = format!("sqlite:{}?mode=rwc", path.display());
+        let settings = DatabaseSettings {
+            journal_mode: SqliteJournalMode::Delete,
+            cache_size: Some(-4096),
+            max_connections: 2,
+            ..Default::default()
+        };
+        let pool = settings
+            .connect(&database_url, DatabaseAccess::ReadWrite)
+            .await
+            .unwrap();
+
+        let mut first = pool.acquire().await.unwrap();
+        let mut second = pool.acquire().await.unwrap();
+        for conn in [&mut first, &mut second] {
+            let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+                .fetch_one(&mut **conn)
+                .await
+                .unwrap();
+            let cache_size: i64 = sqlx::query_scalar("PRAGMA cache_size")
+                .fetch_one(&mut **conn)
+                .await
+                .unwrap();
+            assert_eq!(journal_mode, "delete");
+            assert_eq!(cache_size, -4096);
+        }
+
+        drop((first, second));
+        pool.close().await;
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,204 @@
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::models::*;
+
+pub const CURRENT_VERSION: u32 = 1;
+
+/// Wire format of every message published to the writer queue.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MessageEnvelope {
+    pub version: u32,
+    /// Id the API returned for this write, under which the writer records
+    /// its status. Messages without one are recorded under the queue's
+    /// message id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operation_id: Option<String>,
+    pub operation: String,
+    pub entity: String,
+    pub payload: serde_json::Value,
+    pub produced_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub enum EnvelopeError {
+    Malformed(serde_json::Error),
+    UnsupportedVersion(u32),
+    UnknownOperation { entity: String, operation: String },
+    InvalidPayload(serde_json::Error),
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::Malformed(e) => write!(f, "malformed message: {}", e),
+            EnvelopeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported envelope version {}", version)
+            }
+            EnvelopeError::UnknownOperation { entity, operation } => {
+                write!(f, "unknown operation {} on entity {}", operation, entity)
+            }
+            EnvelopeError::InvalidPayload(e) => write!(f, "invalid payload: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+/// A decoded message: the operation and the id it was accepted under.
+#[derive(Clone, Debug)]
+pub struct QueuedMessage {
+    pub operation_id: Option<String>,
+    pub operation: QueuedOperation,
+}
+
+impl MessageEnvelope {
+    pub fn new(operation_id: &str, operation: &QueuedOperation) -> Result<Self, serde_json::Error> {
+        let payload = match operation {
+            QueuedOperation::CreateUser(user) => serde_json::to_value(user),
+            QueuedOperation::UpdateUser(update) => serde_json::to_value(update),
+            QueuedOperation::DeleteUser(delete) => serde_json::to_value(delete),
+        }?;
+
+        Ok(MessageEnvelope {
+            version: CURRENT_VERSION,
+            operation_id: Some(operation_id.to_string()),
+            operation: operation.operation_type().to_string(),
+            entity: operation.entity_type().to_string(),
+            payload,
+            produced_at: Utc::now(),
+        })
+    }
+
+    pub fn into_message(self) -> Result<QueuedMessage, EnvelopeError> {
+        if self.version != CURRENT_VERSION {
+            return Err(EnvelopeError::UnsupportedVersion(self.version));
+        }
+
+        let operation = match (self.entity.as_str(), self.operation.as_str()) {
+            ("user", "create") => {
+                serde_json::from_value(self.payload).map(QueuedOperation::CreateUser)
+            }
+            ("user", "update") => {
+                serde_json::from_value(self.payload).map(QueuedOperation::UpdateUser)
+            }
+            ("user", "delete") => {
+                serde_json::from_value(self.payload).map(QueuedOperation::DeleteUser)
+            }
+            _ => {
+                return Err(EnvelopeError::UnknownOperation {
+                    entity: self.entity,
+                    operation: self.operation,
+                })
+            }
+        };
+
+        Ok(QueuedMessage {
+            operation_id: self.operation_id,
+            operation: operation.map_err(EnvelopeError::InvalidPayload)?,
+        })
+    }
+}
+
+/// Decodes a queued message body. Bodies without a `version` field predate the
+/// envelope and are read as a bare `QueuedUser` creation.
+pub fn decode_message(body: &str) -> Result<QueuedMessage, EnvelopeError> {
+    let value: serde_json::Value = serde_json::from_str(body).map_err(EnvelopeError::Malformed)?;
+
+    if value.get("version").is_none() {
+        return serde_json::from_value::<QueuedUser>(value)
+            .map(|user| QueuedMessage {
+                operation_id: None,
+                operation: QueuedOperation::CreateUser(user),
+            })
+            .map_err(EnvelopeError::Malformed);
+    }
+
+    serde_json::from_value::<MessageEnvelope>(value)
+        .map_err(EnvelopeError::Malformed)?
+        .into_message()
+}
+
+pub fn encode_message(
+    operation_id: &str,
+    operation: &QueuedOperation,
+) -> Result<String, serde_json::Error> {
+    serde_json::to_string(&MessageEnvelope::new(operation_id, operation)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn envelope_round_trips_operations() {
+        let operation = QueuedOperation::UpdateUser(QueuedUserUpdate {
+            id: "abc123".to_string(),
+            name: Some("renamed".to_string()),
+            email: None,
+        });
+
+        let body = encode_message("op1", &operation).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(value["version"], CURRENT_VERSION);
+        assert_eq!(value["operation_id"], "op1");
+        assert_eq!(value["operation"], "update");
+        assert_eq!(value["entity"], "user");
+        assert!(value["produced_at"].is_string());
+
+        let message = decode_message(&body).unwrap();
+        assert_eq!(message.operation_id.as_deref(), Some("op1"));
+        match message.operation {
+            QueuedOperation::UpdateUser(update) => {
+                assert_eq!(update.id, "abc123");
+                assert_eq!(update.name.as_deref(), Some("renamed"));
+                assert_eq!(update.email, None);
+            }
+            other => panic!("unexpected operation {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bare_queued_user_is_read_as_creation() {
+        let body = r#"{"id":"abc123","name":"test","email":"test@example.com"}"#;
+
+        let message = decode_message(body).unwrap();
+        assert_eq!(message.operation_id, None);
+        match message.operation {
+            QueuedOperation::CreateUser(user) => assert_eq!(user.id, "abc123"),
+            other => panic!("unexpected operation {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_versions_and_operations_are_rejected() {
+        let body = json!({
+            "version": 99,
+            "operation": "create",
+            "entity": "user",
+            "payload": {},
+            "produced_at": "2024-01-01T00:00:00Z",
+        })
+        .to_string();
+        assert!(matches!(
+            decode_message(&body),
+            Err(EnvelopeError::UnsupportedVersion(99))
+        ));
+
+        let body = json!({
+            "version": CURRENT_VERSION,
+            "operation": "merge",
+            "entity": "user",
+            "payload": {},
+            "produced_at": "2024-01-01T00:00:00Z",
+        })
+        .to_string();
+        assert!(matches!(
+            decode_message(&body),
+            Err(EnvelopeError::UnknownOperation { .. })
+        ));
+    }
+}
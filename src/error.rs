@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::rejection::{JsonRejection, QueryRejection},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use validator::ValidationErrors;
+
+use crate::{sqs::PublishError, validation::field_errors, writer::ApplyError};
+
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+const SQLITE_READONLY: i32 = 8;
+
+/// Seconds clients are asked to wait before retrying when the database is busy.
+const RETRY_AFTER_SECONDS: &str = "1";
+
+/// Errors returned by the HTTP handlers of both binaries, rendered as
+/// `application/problem+json` (RFC 9457) with a machine-readable `code`.
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    NotFound,
+    Conflict(String),
+    UnsupportedMediaType(String),
+    Unprocessable(String),
+    Validation(BTreeMap<String, Vec<String>>),
+    Unavailable,
+    /// A write reached an instance that opened the database read-only.
+    ReadOnly,
+    /// The writer has not migrated the database this instance reads yet.
+    NotMigrated,
+    Internal,
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Unprocessable(_) | ApiError::Validation(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ApiError::Unavailable | ApiError::ReadOnly | ApiError::NotMigrated => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::NotFound => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::Unprocessable(_) => "unprocessable_entity",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::Unavailable => "database_busy",
+            ApiError::ReadOnly => "read_only",
+            ApiError::NotMigrated => "not_migrated",
+            ApiError::Internal => "internal_error",
+        }
+    }
+
+    fn detail(&self) -> &str {
+        match self {
+            ApiError::BadRequest(detail)
+            | ApiError::Conflict(detail)
+            | ApiError::UnsupportedMediaType(detail)
+            | ApiError::Unprocessable(detail) => detail,
+            ApiError::NotFound => "resource not found",
+            ApiError::Validation(_) => "request has invalid fields",
+            ApiError::Unavailable => "database is busy, try again later",
+            ApiError::ReadOnly => "this instance only serves reads",
+            ApiError::NotMigrated => "database is not migrated yet, try again later",
+            ApiError::Internal => "something went wrong",
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let mut body = json!({
+            "type": "about:blank",
+            "title": status.canonical_reason().unwrap_or_default(),
+            "status": status.as_u16(),
+            "code": self.code(),
+            "detail": self.detail(),
+        });
+        if let ApiError::Validation(errors) = &self {
+            body["errors"] = json!(errors);
+        }
+
+        let mut response = (status, Json(body)).into_response();
+        let headers = response.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+        if let ApiError::Unavailable | ApiError::NotMigrated = self {
+            headers.insert(
+                header::RETRY_AFTER,
+                HeaderValue::from_static(RETRY_AFTER_SECONDS),
+            );
+        }
+
+        response
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::RowNotFound => ApiError::NotFound,
+            sqlx::Error::PoolTimedOut => ApiError::Unavailable,
+            sqlx::Error::Database(db)
+                if db.is_unique_violation()
+                    || db.is_foreign_key_violation()
+                    || db.is_check_violation() =>
+            {
+                ApiError::Conflict("resource conflicts with an existing one".to_string())
+            }
+            sqlx::Error::Database(db) if is_busy(db.code().as_deref()) => ApiError::Unavailable,
+            sqlx::Error::Database(db) if is_read_only(db.code().as_deref()) => {
+                tracing::error!("Write refused by the read-only database: {}", e);
+                ApiError::ReadOnly
+            }
+            _ => {
+                tracing::error!("Database error: {}", e);
+                ApiError::Internal
+            }
+        }
+    }
+}
+
+impl From<ApplyError> for ApiError {
+    fn from(e: ApplyError) -> Self {
+        match e {
+            ApplyError::NotFound => ApiError::NotFound,
+            ApplyError::Conflict | ApplyError::EmailTaken => ApiError::Conflict(e.to_string()),
+            ApplyError::Lease(e) => {
+                tracing::warn!("Write refused: {}", e);
+                ApiError::Unavailable
+            }
+            ApplyError::Database(e) => e.into(),
+        }
+    }
+}
+
+impl From<PublishError> for ApiError {
+    fn from(e: PublishError) -> Self {
+        match e {
+            PublishError::Rejected(e) => e.into(),
+            PublishError::Failed(reason) => {
+                tracing::error!("Failed to publish message: {}", reason);
+                ApiError::Unavailable
+            }
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection {
+            JsonRejection::JsonDataError(e) => ApiError::Unprocessable(e.body_text()),
+            JsonRejection::MissingJsonContentType(e) => {
+                ApiError::UnsupportedMediaType(e.body_text())
+            }
+            other => ApiError::BadRequest(other.body_text()),
+        }
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::BadRequest(rejection.body_text())
+    }
+}
+
+impl From<ValidationErrors> for ApiError {
+    fn from(errors: ValidationErrors) -> Self {
+        ApiError::Validation(field_errors(&errors))
+    }
+}
+
+/// SQLite reports extended result codes; the primary code is the low byte.
+fn is_busy(code: Option<&str>) -> bool {
+    code.and_then(|c| c.parse::<i32>().ok())
+        .map(|c| matches!(c & 0xff, SQLITE_BUSY | SQLITE_LOCKED))
+        .unwrap_or(false)
+}
+
+fn is_read_only(code: Option<&str>) -> bool {
+    code.and_then(|c| c.parse::<i32>().ok())
+        .map(|c| c & 0xff == SQLITE_READONLY)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn unavailable_is_problem_json_with_retry_after() {
+        let response = ApiError::Unavailable.into_response();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        assert_eq!(response.headers()[header::RETRY_AFTER], RETRY_AFTER_SECONDS);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], 503);
+        assert_eq!(body["code"], "database_busy");
+        assert_eq!(body["title"], "Service Unavailable");
+    }
+
+    #[test]
+    fn busy_codes_include_extended_codes() {
+        assert!(is_busy(Some("5")));
+        assert!(is_busy(Some("517")));
+        assert!(is_busy(Some("6")));
+        assert!(!is_busy(Some("19")));
+        assert!(!is_busy(None));
+    }
+
+    #[sqlx::test]
+    async fn unique_violation_maps_to_conflict(pool: sqlx::SqlitePool) {
+        let insert = || {
+            sqlx::query("INSERT INTO users (id, name, email) VALUES ('dup', 'a', 'a@example.com')")
+                .execute(&pool)
+        };
+        insert().await.unwrap();
+        let err: ApiError = insert().await.unwrap_err().into();
+
+        assert!(matches!(err, ApiError::Conflict(_)));
+    }
+
+    #[test]
+    fn publish_failures_ask_clients_to_retry() {
+        let err: ApiError = PublishError::Failed("queue unreachable".to_string()).into();
+
+        assert!(matches!(err, ApiError::Unavailable));
+    }
+}
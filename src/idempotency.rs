@@ -0,0 +1,211 @@
+use std::future::Future;
+
+use axum::{
+    async_trait,
+    body::{to_bytes, Body},
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, Pool, Sqlite};
+
+use crate::error::ApiError;
+
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Set on responses replayed from a previous request with the same key.
+pub const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+
+const MAX_KEY_LENGTH: usize = 255;
+/// Response headers replayed along with the status and body.
+const STORED_HEADERS: &[HeaderName] = &[header::CONTENT_TYPE, header::LOCATION];
+const TTL: &str = "+24 hours";
+
+/// Response extension marking a result that only failed for now, such as bulk
+/// rows that could not be enqueued. Like server errors, it is not stored, so
+/// a retry with the same key runs the request again.
+#[derive(Clone, Copy, Debug)]
+pub struct Retryable;
+
+/// The optional `Idempotency-Key` request header.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IdempotencyKey(pub Option<String>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for IdempotencyKey {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(IDEMPOTENCY_KEY) else {
+            return Ok(IdempotencyKey(None));
+        };
+
+        match value.to_str() {
+            Ok(key) if !key.trim().is_empty() && key.len() <= MAX_KEY_LENGTH => {
+                Ok(IdempotencyKey(Some(key.to_string())))
+            }
+            _ => Err(ApiError::BadRequest(format!(
+                "Idempotency-Key must be 1 to {} visible ASCII characters",
+                MAX_KEY_LENGTH
+            ))),
+        }
+    }
+}
+
+/// Identifies a request by method, path and payload, so a key reused for a
+/// different request can be told apart from a retry.
+pub fn fingerprint(method: &str, path: &str, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_bytes());
+    hasher.update([0]);
+    hasher.update(path.as_bytes());
+    hasher.update([0]);
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+/// Runs `handler` once per idempotency key. The first response for a key is
+/// stored and replayed for retries with the same fingerprint until it
+/// expires; a different fingerprint is rejected with 422. Server errors and
+/// [`Retryable`] responses are not stored, so those requests can be retried
+/// with the same key.
+pub async fn run<R: IntoResponse>(
+    pool: &Pool<Sqlite>,
+    key: IdempotencyKey,
+    fingerprint: String,
+    handler: impl Future<Output = R>,
+) -> Result<Response, ApiError> {
+    let Some(key) = key.0 else {
+        return Ok(handler.await.into_response());
+    };
+
+    sqlx::query("DELETE FROM idempotency_keys WHERE expires_at < CURRENT_TIMESTAMP")
+        .execute(pool)
+        .await?;
+
+    let reserved = sqlx::query(
+        "INSERT INTO idempotency_keys (key, fingerprint, expires_at)
+        VALUES ($1, $2, datetime('now', $3))
+        ON CONFLICT (key) DO NOTHING",
+    )
+    .bind(&key)
+    .bind(&fingerprint)
+    .bind(TTL)
+    .execute(pool)
+    .await?
+    .rows_affected()
+        == 1;
+
+    if !reserved {
+        return replay(pool, &key, &fingerprint).await;
+    }
+
+    let response = handler.await.into_response();
+    match store(pool, &key, response).await {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            tracing::error!("Failed to store idempotent response for {}: {}", key, e);
+            release(pool, &key).await;
+            Err(ApiError::Internal)
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct StoredResponse {
+    fingerprint: String,
+    status: Option<i64>,
+    headers: Option<String>,
+    body: Option<Vec<u8>>,
+}
+
+async fn replay(pool: &Pool<Sqlite>, key: &str, fingerprint: &str) -> Result<Response, ApiError> {
+    let stored: Option<StoredResponse> = sqlx::query_as(
+        "SELECT fingerprint, status, headers, body FROM idempotency_keys WHERE key = $1",
+    )
+    .bind(key)
+    .fetch_optional(pool)
+    .await?;
+
+    match stored {
+        Some(stored) if stored.fingerprint != fingerprint => Err(ApiError::Unprocessable(
+            "Idempotency-Key was already used for a different request".to_string(),
+        )),
+        Some(StoredResponse {
+            status: Some(status),
+            headers: stored_headers,
+            body,
+            ..
+        }) => {
+            let status = u16::try_from(status)
+                .ok()
+                .and_then(|status| StatusCode::from_u16(status).ok())
+                .ok_or(ApiError::Internal)?;
+
+            let stored_headers: Vec<(String, String)> = match stored_headers {
+                Some(json) => serde_json::from_str(&json).map_err(|_| ApiError::Internal)?,
+                None => Vec::new(),
+            };
+
+            let mut response = (status, body.unwrap_or_default()).into_response();
+            let headers = response.headers_mut();
+            headers.remove(header::CONTENT_TYPE);
+            for (name, value) in stored_headers {
+                if let (Ok(name), Ok(value)) =
+                    (HeaderName::try_from(name), HeaderValue::try_from(value))
+                {
+                    headers.append(name, value);
+                }
+            }
+            headers.insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+
+            Ok(response)
+        }
+        // Either still in flight, or it failed and was released meanwhile.
+        _ => Err(ApiError::Conflict(
+            "a request with this Idempotency-Key is still being processed".to_string(),
+        )),
+    }
+}
+
+/// Records a finished response under `key`, or releases the key when the
+/// response is a server error or [`Retryable`].
+async fn store(pool: &Pool<Sqlite>, key: &str, response: Response) -> Result<Response, String> {
+    if response.status().is_server_error() || response.extensions().get::<Retryable>().is_some() {
+        release(pool, key).await;
+        return Ok(response);
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| e.to_string())?;
+    let headers: Vec<(&str, &str)> = parts
+        .headers
+        .iter()
+        .filter(|(name, _)| STORED_HEADERS.contains(name))
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+        .collect();
+    let headers = serde_json::to_string(&headers).map_err(|e| e.to_string())?;
+
+    sqlx::query("UPDATE idempotency_keys SET status = $2, headers = $3, body = $4 WHERE key = $1")
+        .bind(key)
+        .bind(parts.status.as_u16() as i64)
+        .bind(headers)
+        .bind(body.as_ref())
+        .execute(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+async fn release(pool: &Pool<Sqlite>, key: &str) {
+    if let Err(e) = sqlx::query("DELETE FROM idempotency_keys WHERE key = $1")
+        .bind(key)
+        .execute(pool)
+        .await
+    {
+        tracing::warn!("Failed to release idempotency key {}: {}", key, e);
+    }
+}
@@ -0,0 +1,181 @@
+//! Entrypoints that talk to the Lambda runtime API directly, without the
+//! Lambda Web Adapter layer. Built with the `lambda-runtime` feature.
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    body::{Body, BodyDataStream},
+    http::{header, HeaderName, HeaderValue, Method, Request},
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lambda_runtime::{
+    service_fn, tower::ServiceExt, Error, LambdaEvent, MetadataPrelude, StreamResponse,
+};
+use serde::Deserialize;
+
+use crate::{
+    api,
+    models::{AppState, SqsBatchResponse, SqsEvent},
+    writer,
+};
+
+/// The parts of a function URL (payload format 2.0) event the API needs.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FunctionUrlRequest {
+    pub raw_path: String,
+    #[serde(default)]
+    pub raw_query_string: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub cookies: Vec<String>,
+    pub body: Option<String>,
+    #[serde(default)]
+    pub is_base64_encoded: bool,
+    pub request_context: RequestContext,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct RequestContext {
+    pub http: HttpContext,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct HttpContext {
+    pub method: String,
+}
+
+/// Serves the API router for function URL invocations. Responses are
+/// streamed, matching the `RESPONSE_STREAM` invoke mode of the function URL.
+pub async fn run_api(state: Arc<AppState>) -> Result<(), Error> {
+    let router = api::app(state);
+    lambda_runtime::run(service_fn(|event: LambdaEvent<FunctionUrlRequest>| {
+        handle_request(router.clone(), event.payload)
+    }))
+    .await
+}
+
+/// Applies SQS events delivered by the event source mapping, reporting
+/// failed records as batch item failures.
+pub async fn run_writer(state: Arc<AppState>) -> Result<(), Error> {
+    lambda_runtime::run(service_fn(|event: LambdaEvent<SqsEvent>| {
+        let state = state.clone();
+        async move {
+            Ok::<SqsBatchResponse, Error>(
+                writer::process_event(&state.pool, &state.lease, &event.payload).await,
+            )
+        }
+    }))
+    .await
+}
+
+pub async fn handle_request(
+    router: Router,
+    event: FunctionUrlRequest,
+) -> Result<StreamResponse<BodyDataStream>, Error> {
+    let request = to_http_request(event)?;
+    let response = router.oneshot(request).await?;
+
+    let (parts, body) = response.into_parts();
+    let mut headers = parts.headers;
+    // Function URLs take cookies separately from the other headers.
+    let cookies = headers
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok().map(str::to_string))
+        .collect();
+    headers.remove(header::SET_COOKIE);
+
+    Ok(StreamResponse {
+        metadata_prelude: MetadataPrelude {
+            status_code: parts.status,
+            headers,
+            cookies,
+        },
+        stream: body.into_data_stream(),
+    })
+}
+
+pub fn to_http_request(event: FunctionUrlRequest) -> Result<Request<Body>, Error> {
+    let uri = match event.raw_query_string.as_str() {
+        "" => event.raw_path,
+        query => format!("{}?{}", event.raw_path, query),
+    };
+
+    let mut request = Request::builder()
+        .method(Method::from_bytes(
+            event.request_context.http.method.as_bytes(),
+        )?)
+        .uri(uri);
+
+    for (name, value) in &event.headers {
+        request = request.header(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(value)?,
+        );
+    }
+    if !event.cookies.is_empty() {
+        request = request.header(header::COOKIE, event.cookies.join("; "));
+    }
+
+    let body = match event.body {
+        Some(body) if event.is_base64_encoded => Body::from(STANDARD.decode(body)?),
+        Some(body) => Body::from(body),
+        None => Body::empty(),
+    };
+
+    Ok(request.body(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[test]
+    fn function_url_events_become_http_requests() {
+        let event: FunctionUrlRequest = serde_json::from_value(serde_json::json!({
+            "rawPath": "/users",
+            "rawQueryString": "limit=2&sort=-name",
+            "headers": {"content-type": "application/json"},
+            "cookies": ["a=1", "b=2"],
+            "body": STANDARD.encode(r#"{"name":"Ada"}"#),
+            "isBase64Encoded": true,
+            "requestContext": {"http": {"method": "POST"}}
+        }))
+        .unwrap();
+
+        let request = to_http_request(event).unwrap();
+
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.uri(), "/users?limit=2&sort=-name");
+        assert_eq!(request.headers()[header::COOKIE], "a=1; b=2");
+        assert_eq!(request.headers()[header::CONTENT_TYPE], "application/json");
+    }
+
+    #[sqlx::test]
+    async fn requests_are_served_by_the_api_router(pool: sqlx::SqlitePool) {
+        let router = api::create_router().with_state(Arc::new(AppState::new(pool)));
+        let event = FunctionUrlRequest {
+            raw_path: "/health-check".to_string(),
+            request_context: RequestContext {
+                http: HttpContext {
+                    method: "GET".to_string(),
+                },
+            },
+            ..Default::default()
+        };
+
+        let response = handle_request(router, event).await.unwrap();
+        assert_eq!(response.metadata_prelude.status_code, 200);
+
+        let body = Body::from_stream(response.stream)
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes();
+        assert_eq!(&body[..], br#"{"message":"ok"}"#);
+    }
+}
@@ -0,0 +1,518 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions, TryLockError},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::Utc;
+use serde_json::json;
+use sqlx::{Pool, Sqlite, SqliteConnection, Transaction};
+
+use crate::{config::parse_bool, db::DEFAULT_DATABASE_PATH, id::generate_xid_string};
+
+/// Only one lease exists: the right to write to the database.
+pub const LEASE_NAME: &str = "writer";
+pub const DEFAULT_TTL: Duration = Duration::from_secs(30);
+
+/// CloudWatch namespace of the metrics emitted in embedded metric format.
+const METRICS_NAMESPACE: &str = "LambdaRustSqlite";
+
+/// Lease behaviour, part of [`crate::config::Config`]:
+///
+/// - `WRITER_LEASE_TTL_SECONDS`: how long a lease outlives the last write of
+///   its holder before another writer may take it over.
+/// - `WRITER_LEASE_LOCK_FILE`: also hold an advisory lock on
+///   `DATABASE_PATH` + `.lock` while writing, when `true`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LeaseSettings {
+    pub ttl: Duration,
+    pub lock_file: Option<PathBuf>,
+}
+
+impl Default for LeaseSettings {
+    fn default() -> Self {
+        LeaseSettings {
+            ttl: DEFAULT_TTL,
+            lock_file: None,
+        }
+    }
+}
+
+impl LeaseSettings {
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let ttl = match lookup("WRITER_LEASE_TTL_SECONDS") {
+            Some(value) => match value.parse::<u64>() {
+                Ok(seconds) if (1..=3600).contains(&seconds) => Duration::from_secs(seconds),
+                _ => {
+                    return Err(format!(
+                        "WRITER_LEASE_TTL_SECONDS must be an integer from 1 to 3600, got {:?}",
+                        value
+                    ))
+                }
+            },
+            None => DEFAULT_TTL,
+        };
+
+        let lock_file = match parse_bool(&lookup, "WRITER_LEASE_LOCK_FILE")? {
+            Some(true) => {
+                let database_path =
+                    lookup("DATABASE_PATH").unwrap_or_else(|| DEFAULT_DATABASE_PATH.to_string());
+                Some(PathBuf::from(format!("{}.lock", database_path)))
+            }
+            Some(false) | None => None,
+        };
+
+        Ok(LeaseSettings { ttl, lock_file })
+    }
+}
+
+#[derive(Debug)]
+pub enum LeaseError {
+    /// Another writer holds a lease that has not expired yet.
+    Held {
+        holder_id: String,
+        expires_at: String,
+    },
+    /// Another process holds the advisory lock file.
+    LockFileHeld(PathBuf),
+    /// The lease changed hands during the transaction, which was rolled back.
+    Fenced {
+        token: i64,
+        current: Option<i64>,
+    },
+    Io(std::io::Error),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for LeaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LeaseError::Held {
+                holder_id,
+                expires_at,
+            } => write!(
+                f,
+                "writer lease is held by {} until {}",
+                holder_id, expires_at
+            ),
+            LeaseError::LockFileHeld(path) => {
+                write!(f, "lock file {} is held by another process", path.display())
+            }
+            LeaseError::Fenced { token, current } => write!(
+                f,
+                "writer lease was taken over, fencing token {} is now {:?}",
+                token, current
+            ),
+            LeaseError::Io(e) => write!(f, "failed to lock file: {}", e),
+            LeaseError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for LeaseError {
+    fn from(e: sqlx::Error) -> Self {
+        LeaseError::Database(e)
+    }
+}
+
+/// The right of this process to write to the shared database. SQLite
+/// locking is not reliable over NFS, so every write transaction first renews
+/// the lease row; a writer that finds the lease held by someone else backs
+/// off. The fencing token increases each time the lease changes hands, so a
+/// writer that was paused past its expiry sees that it was taken over.
+pub struct WriterLease {
+    holder_id: String,
+    settings: LeaseSettings,
+    /// Token of the last successful acquisition, 0 while not held.
+    fencing_token: AtomicI64,
+    lock_file: Mutex<LockFile>,
+    contended: AtomicU64,
+}
+
+/// The advisory lock file, open while at least one transaction of this
+/// process holds the lease.
+#[derive(Default)]
+struct LockFile {
+    file: Option<File>,
+    holders: usize,
+}
+
+/// Proof that the lease was acquired for one write transaction. Keep it
+/// until the transaction has committed or rolled back: dropping it lets go
+/// of the lock file, so a paused writer never keeps it past its transaction.
+pub struct LeaseGuard<'a> {
+    lease: &'a WriterLease,
+    token: i64,
+    locked: bool,
+}
+
+impl LeaseGuard<'_> {
+    pub fn fencing_token(&self) -> i64 {
+        self.token
+    }
+
+    /// Commits `tx` only if the lease still carries this guard's fencing
+    /// token, checked inside the transaction, and rolls it back otherwise: a
+    /// writer paused past its expiry must not commit over the next holder.
+    pub async fn commit(self, mut tx: Transaction<'_, Sqlite>) -> Result<(), LeaseError> {
+        let current: Option<i64> =
+            sqlx::query_scalar("SELECT fencing_token FROM writer_lease WHERE name = $1")
+                .bind(LEASE_NAME)
+                .fetch_optional(&mut *tx)
+                .await?;
+
+        if current != Some(self.token) {
+            tx.rollback().await?;
+            let e = LeaseError::Fenced {
+                token: self.token,
+                current,
+            };
+            self.lease.fencing_token.store(0, Ordering::SeqCst);
+            self.lease.record_contention(&e);
+            return Err(e);
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+impl Drop for LeaseGuard<'_> {
+    fn drop(&mut self) {
+        if self.locked {
+            self.lease.unlock_file();
+        }
+    }
+}
+
+impl WriterLease {
+    pub fn new(settings: LeaseSettings) -> Self {
+        WriterLease {
+            holder_id: generate_xid_string(),
+            settings,
+            fencing_token: AtomicI64::new(0),
+            lock_file: Mutex::new(LockFile::default()),
+            contended: AtomicU64::new(0),
+        }
+    }
+
+    pub fn holder_id(&self) -> &str {
+        &self.holder_id
+    }
+
+    pub fn fencing_token(&self) -> Option<i64> {
+        match self.fencing_token.load(Ordering::SeqCst) {
+            0 => None,
+            token => Some(token),
+        }
+    }
+
+    /// Times this writer found the lease held by someone else.
+    pub fn contended(&self) -> u64 {
+        self.contended.load(Ordering::SeqCst)
+    }
+
+    /// Acquires or renews the lease and returns a guard with its fencing
+    /// token. Run it as the first statement of the write transaction: the
+    /// lease is then held for as long as the transaction's writes, and rolled
+    /// back with them.
+    pub async fn acquire(&self, conn: &mut SqliteConnection) -> Result<LeaseGuard<'_>, LeaseError> {
+        let locked = match self.lock_file() {
+            Ok(locked) => locked,
+            Err(e) => {
+                if let LeaseError::LockFileHeld(_) = e {
+                    self.record_contention(&e);
+                }
+                return Err(e);
+            }
+        };
+        let mut guard = LeaseGuard {
+            lease: self,
+            token: 0,
+            locked,
+        };
+
+        let token: Option<i64> = sqlx::query_scalar(
+            "INSERT INTO writer_lease (name, holder_id, fencing_token, expires_at) VALUES ($1, $2, 1, datetime('now', $3))
+             ON CONFLICT (name) DO UPDATE SET
+                 fencing_token = CASE WHEN holder_id = excluded.holder_id THEN fencing_token ELSE fencing_token + 1 END,
+                 acquired_at = CASE WHEN holder_id = excluded.holder_id THEN acquired_at ELSE CURRENT_TIMESTAMP END,
+                 holder_id = excluded.holder_id,
+                 expires_at = excluded.expires_at
+             WHERE holder_id = excluded.holder_id OR expires_at <= CURRENT_TIMESTAMP
+             RETURNING fencing_token",
+        )
+        .bind(LEASE_NAME)
+        .bind(&self.holder_id)
+        .bind(format!("+{} seconds", self.settings.ttl.as_secs()))
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        let Some(token) = token else {
+            let (holder_id, expires_at): (String, String) =
+                sqlx::query_as("SELECT holder_id, expires_at FROM writer_lease WHERE name = $1")
+                    .bind(LEASE_NAME)
+                    .fetch_one(&mut *conn)
+                    .await?;
+            let e = LeaseError::Held {
+                holder_id,
+                expires_at,
+            };
+            self.fencing_token.store(0, Ordering::SeqCst);
+            self.record_contention(&e);
+            return Err(e);
+        };
+
+        let previous = self.fencing_token.swap(token, Ordering::SeqCst);
+        if previous != token {
+            if previous != 0 {
+                tracing::warn!(
+                    "Writer lease changed hands since token {}, {} now holds token {}",
+                    previous,
+                    self.holder_id,
+                    token
+                );
+            } else {
+                tracing::info!(
+                    "Writer {} acquired the lease with token {}",
+                    self.holder_id,
+                    token
+                );
+            }
+        }
+
+        guard.token = token;
+
+        Ok(guard)
+    }
+
+    /// Gives the lease up so the next writer does not wait for it to expire.
+    pub async fn release(&self, pool: &Pool<Sqlite>) {
+        let result = sqlx::query(
+            "UPDATE writer_lease SET expires_at = CURRENT_TIMESTAMP WHERE name = $1 AND holder_id = $2",
+        )
+        .bind(LEASE_NAME)
+        .bind(&self.holder_id)
+        .execute(pool)
+        .await;
+
+        if let Err(e) = result {
+            tracing::warn!("Failed to release the writer lease: {}", e);
+        }
+        self.fencing_token.store(0, Ordering::SeqCst);
+    }
+
+    /// Takes the advisory lock file when one is configured, returning
+    /// whether it did. Transactions of this process share the lock; it is
+    /// given up when the last of them drops its [`LeaseGuard`].
+    fn lock_file(&self) -> Result<bool, LeaseError> {
+        let Some(path) = &self.settings.lock_file else {
+            return Ok(false);
+        };
+
+        let mut held = self.lock_file.lock().unwrap();
+        if held.file.is_some() {
+            held.holders += 1;
+            return Ok(true);
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .map_err(LeaseError::Io)?;
+        match file.try_lock() {
+            Ok(()) => {
+                held.file = Some(file);
+                held.holders = 1;
+                Ok(true)
+            }
+            Err(TryLockError::WouldBlock) => Err(LeaseError::LockFileHeld(path.clone())),
+            Err(TryLockError::Error(e)) => Err(LeaseError::Io(e)),
+        }
+    }
+
+    fn unlock_file(&self) {
+        let mut held = self.lock_file.lock().unwrap();
+        held.holders -= 1;
+        if held.holders == 0 {
+            held.file = None;
+        }
+    }
+
+    fn record_contention(&self, e: &LeaseError) {
+        let contended = self.contended.fetch_add(1, Ordering::SeqCst) + 1;
+        tracing::warn!(
+            "Writer {} backed off, {} ({} times so far)",
+            self.holder_id,
+            e,
+            contended
+        );
+
+        // Embedded metric format: CloudWatch turns this log line into a
+        // WriterLeaseContention metric.
+        println!(
+            "{}",
+            json!({
+                "_aws": {
+                    "Timestamp": Utc::now().timestamp_millis(),
+                    "CloudWatchMetrics": [{
+                        "Namespace": METRICS_NAMESPACE,
+                        "Dimensions": [["Lease"]],
+                        "Metrics": [{"Name": "WriterLeaseContention", "Unit": "Count"}],
+                    }],
+                },
+                "Lease": LEASE_NAME,
+                "WriterLeaseContention": 1,
+                "holder_id": self.holder_id,
+            })
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn acquire(pool: &Pool<Sqlite>, lease: &WriterLease) -> Result<i64, LeaseError> {
+        let mut tx = pool.begin().await.unwrap();
+        let guard = lease.acquire(&mut tx).await?;
+        let token = guard.fencing_token();
+        guard.commit(tx).await?;
+        Ok(token)
+    }
+
+    #[sqlx::test]
+    async fn live_lease_rejects_other_writers(pool: sqlx::SqlitePool) {
+        let first = WriterLease::new(LeaseSettings::default());
+        let second = WriterLease::new(LeaseSettings::default());
+
+        assert_eq!(acquire(&pool, &first).await.unwrap(), 1);
+        assert_eq!(acquire(&pool, &first).await.unwrap(), 1);
+
+        let rejected = acquire(&pool, &second).await;
+        assert!(
+            matches!(rejected, Err(LeaseError::Held { holder_id, .. }) if holder_id == first.holder_id())
+        );
+        assert_eq!(second.contended(), 1);
+
+        first.release(&pool).await;
+        assert_eq!(acquire(&pool, &second).await.unwrap(), 2);
+
+        // The first writer is now the stale holder.
+        assert!(acquire(&pool, &first).await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn expired_lease_is_taken_over_with_a_new_token(pool: sqlx::SqlitePool) {
+        let paused = WriterLease::new(LeaseSettings {
+            ttl: Duration::ZERO,
+            ..Default::default()
+        });
+        let next = WriterLease::new(LeaseSettings::default());
+
+        assert_eq!(acquire(&pool, &paused).await.unwrap(), 1);
+        assert_eq!(acquire(&pool, &next).await.unwrap(), 2);
+        assert_eq!(next.fencing_token(), Some(2));
+    }
+
+    #[sqlx::test]
+    async fn taken_over_lease_cannot_commit(pool: sqlx::SqlitePool) {
+        let paused = WriterLease::new(LeaseSettings::default());
+        let mut tx = pool.begin().await.unwrap();
+        let guard = paused.acquire(&mut tx).await.unwrap();
+
+        sqlx::query("INSERT INTO users (id, name, email) VALUES ('f1', 'a', 'a@example.com')")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        // What another writer does once the lease has expired.
+        sqlx::query(
+            "UPDATE writer_lease SET holder_id = 'next', fencing_token = fencing_token + 1",
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            guard.commit(tx).await,
+            Err(LeaseError::Fenced {
+                token: 1,
+                current: Some(2)
+            })
+        ));
+        assert_eq!(paused.fencing_token(), None);
+        let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(users, 0);
+    }
+
+    #[tokio::test]
+    async fn lock_file_is_exclusive() {
+        let path = std::env::temp_dir().join(format!("lease-{}.lock", generate_xid_string()));
+        let settings = LeaseSettings {
+            lock_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let first = WriterLease::new(settings.clone());
+        let second = WriterLease::new(settings);
+
+        assert!(first.lock_file().unwrap());
+        assert!(first.lock_file().unwrap());
+        assert!(matches!(
+            second.lock_file(),
+            Err(LeaseError::LockFileHeld(_))
+        ));
+
+        // Held until the last transaction of the first writer lets go.
+        first.unlock_file();
+        assert!(second.lock_file().is_err());
+        first.unlock_file();
+        assert!(second.lock_file().unwrap());
+        second.unlock_file();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[sqlx::test]
+    async fn lock_file_is_released_with_the_transaction(pool: sqlx::SqlitePool) {
+        let path = std::env::temp_dir().join(format!("lease-{}.lock", generate_xid_string()));
+        let settings = LeaseSettings {
+            ttl: Duration::ZERO,
+            lock_file: Some(path.clone()),
+        };
+        let paused = WriterLease::new(settings.clone());
+        let next = WriterLease::new(settings);
+
+        // The first writer keeps its lease row but never writes again; once
+        // the lease expires the next one takes over without waiting for it.
+        assert_eq!(acquire(&pool, &paused).await.unwrap(), 1);
+        assert_eq!(acquire(&pool, &next).await.unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn settings_place_the_lock_file_next_to_the_database() {
+        let settings = LeaseSettings::from_lookup(|key| match key {
+            "WRITER_LEASE_LOCK_FILE" => Some("true".to_string()),
+            "DATABASE_PATH" => Some("/mnt/efs/users.db".to_string()),
+            _ => None,
+        })
+        .unwrap();
+
+        assert_eq!(
+            settings.lock_file,
+            Some(PathBuf::from("/mnt/efs/users.db.lock"))
+        );
+        assert_eq!(settings.ttl, DEFAULT_TTL);
+        assert!(LeaseSettings::from_lookup(|_| Some("0".to_string())).is_err());
+    }
+}
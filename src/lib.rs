@@ -1,6 +1,20 @@
 pub mod api;
+pub mod bulk;
+pub mod config;
 pub mod db;
+pub mod envelope;
+pub mod error;
 pub mod id;
+pub mod idempotency;
+#[cfg(feature = "lambda-runtime")]
+pub mod lambda;
+pub mod lease;
+pub mod listing;
 pub mod models;
+pub mod pagination;
+pub mod poller;
+pub mod search;
 pub mod sqs;
+pub mod streaming;
+pub mod validation;
 pub mod writer;
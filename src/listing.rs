@@ -0,0 +1,303 @@
+use chrono::{DateTime, Utc};
+use sqlx::{QueryBuilder, Sqlite};
+
+use crate::{
+    error::ApiError,
+    models::ListUsersParams,
+    pagination::{page_size, Cursor},
+    validation::normalize_email,
+};
+
+/// Fields that can be requested through `fields`.
+pub const USER_FIELDS: &[&str] = &["id", "name", "email", "created_at", "updated_at"];
+
+const SELECT_USERS: &str = "SELECT id, name, email, created_at, updated_at";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortField {
+    Id,
+    Name,
+    CreatedAt,
+    UpdatedAt,
+}
+
+impl SortField {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "id" => Some(SortField::Id),
+            "name" => Some(SortField::Name),
+            "created_at" => Some(SortField::CreatedAt),
+            "updated_at" => Some(SortField::UpdatedAt),
+            _ => None,
+        }
+    }
+
+    fn column(&self) -> &'static str {
+        match self {
+            SortField::Id => "id",
+            SortField::Name => "name",
+            SortField::CreatedAt => "created_at",
+            SortField::UpdatedAt => "updated_at",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sort {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+impl Sort {
+    /// Parses `field` or `-field` (descending) from the whitelisted fields.
+    fn parse(value: &str) -> Result<Self, ApiError> {
+        let (descending, name) = match value.strip_prefix('-') {
+            Some(name) => (true, name),
+            None => (false, value),
+        };
+
+        let field = SortField::parse(name)
+            .ok_or_else(|| ApiError::BadRequest(format!("cannot sort by {}", name)))?;
+
+        Ok(Sort { field, descending })
+    }
+}
+
+impl Default for Sort {
+    fn default() -> Self {
+        Sort {
+            field: SortField::Id,
+            descending: false,
+        }
+    }
+}
+
+/// A validated `GET /users` request, compiled into parameterized SQL.
+#[derive(Clone, Debug, Default)]
+pub struct UserListing {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub email_domain: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub updated_after: Option<DateTime<Utc>>,
+    pub updated_before: Option<DateTime<Utc>>,
+    pub sort: Sort,
+    pub fields: Option<Vec<String>>,
+    pub limit: u32,
+    pub after: Option<Cursor>,
+}
+
+impl UserListing {
+    pub fn from_params(params: ListUsersParams) -> Result<Self, ApiError> {
+        let sort = params
+            .sort
+            .as_deref()
+            .map(Sort::parse)
+            .transpose()?
+            .unwrap_or_default();
+
+        let fields = params
+            .fields
+            .as_deref()
+            .map(|fields| {
+                fields
+                    .split(',')
+                    .map(|field| field.trim().to_string())
+                    .map(|field| match USER_FIELDS.contains(&field.as_str()) {
+                        true => Ok(field),
+                        false => Err(ApiError::BadRequest(format!("unknown field {}", field))),
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?;
+
+        Ok(UserListing {
+            name: params.name,
+            email: params.email,
+            email_domain: params.email_domain,
+            created_after: params.created_after,
+            created_before: params.created_before,
+            updated_after: params.updated_after,
+            updated_before: params.updated_before,
+            sort,
+            fields,
+            limit: page_size(params.limit),
+            after: params.cursor.as_deref().map(Cursor::decode).transpose()?,
+        })
+    }
+
+    /// Builds the page query. Rows carry the sort column as `sort_key` so the
+    /// next cursor can be built from the last one, and one extra row is
+    /// fetched to tell whether there is a next page.
+    pub fn page_query(&self) -> QueryBuilder<'static, Sqlite> {
+        let mut builder = self.ordered_query();
+        builder.push(" LIMIT ");
+        builder.push_bind(self.limit + 1);
+
+        builder
+    }
+
+    /// Builds the same query as [`UserListing::page_query`] without a page
+    /// size, for streaming every matching row.
+    pub fn stream_query(&self) -> QueryBuilder<'static, Sqlite> {
+        self.ordered_query()
+    }
+
+    fn ordered_query(&self) -> QueryBuilder<'static, Sqlite> {
+        let column = self.sort.field.column();
+        let mut builder = QueryBuilder::new(SELECT_USERS);
+        builder.push(format!(
+            ", CAST({} AS TEXT) AS sort_key FROM users WHERE 1 = 1",
+            column
+        ));
+
+        self.push_filters(&mut builder);
+
+        if let Some(cursor) = &self.after {
+            let op = if self.sort.descending { "<" } else { ">" };
+            match (self.sort.field, &cursor.key) {
+                (SortField::Id, _) | (_, None) => {
+                    builder.push(format!(" AND id {} ", op));
+                    builder.push_bind(cursor.id.clone());
+                }
+                (_, Some(key)) => {
+                    builder.push(format!(" AND ({} {} ", column, op));
+                    builder.push_bind(key.clone());
+                    builder.push(format!(" OR ({} = ", column));
+                    builder.push_bind(key.clone());
+                    builder.push(format!(" AND id {} ", op));
+                    builder.push_bind(cursor.id.clone());
+                    builder.push("))");
+                }
+            }
+        }
+
+        let direction = if self.sort.descending { "DESC" } else { "ASC" };
+        if self.sort.field != SortField::Id {
+            builder.push(format!(" ORDER BY {} {},", column, direction));
+        } else {
+            builder.push(" ORDER BY");
+        }
+        builder.push(format!(" id {}", direction));
+
+        builder
+    }
+
+    /// Appends the `AND ...` conditions for the requested filters.
+    pub fn push_filters(&self, builder: &mut QueryBuilder<'static, Sqlite>) {
+        if let Some(name) = &self.name {
+            builder.push(" AND name LIKE ");
+            builder.push_bind(contains_pattern(name));
+            builder.push(" ESCAPE '\\'");
+        }
+
+        if let Some(email) = &self.email {
+            builder.push(" AND email LIKE ");
+            builder.push_bind(contains_pattern(email));
+            builder.push(" ESCAPE '\\'");
+        }
+
+        if let Some(domain) = &self.email_domain {
+            // Duplicates left by the migration have no normalized email.
+            builder.push(" AND COALESCE(email_normalized, lower(email)) LIKE ");
+            builder.push_bind(format!("%@{}", escape_like(&normalize_email(domain))));
+            builder.push(" ESCAPE '\\'");
+        }
+
+        let ranges = [
+            ("created_at", ">=", self.created_after),
+            ("created_at", "<", self.created_before),
+            ("updated_at", ">=", self.updated_after),
+            ("updated_at", "<", self.updated_before),
+        ];
+        for (column, op, value) in ranges {
+            if let Some(value) = value {
+                builder.push(format!(" AND {} {} ", column, op));
+                builder.push_bind(to_sqlite_timestamp(&value));
+            }
+        }
+    }
+
+    /// The requested fields in request order, or every field.
+    pub fn selected_fields(&self) -> Vec<&str> {
+        match &self.fields {
+            Some(fields) => fields.iter().map(String::as_str).collect(),
+            None => USER_FIELDS.to_vec(),
+        }
+    }
+
+    /// Keeps only the requested fields of each serialized user.
+    pub fn project(&self, users: &mut [serde_json::Value]) {
+        let Some(fields) = &self.fields else {
+            return;
+        };
+
+        for user in users {
+            if let Some(user) = user.as_object_mut() {
+                user.retain(|key, _| fields.contains(key));
+            }
+        }
+    }
+}
+
+/// `CURRENT_TIMESTAMP` stores UTC as `YYYY-MM-DD HH:MM:SS`, which compares
+/// correctly as text only against values in the same format.
+fn to_sqlite_timestamp(value: &DateTime<Utc>) -> String {
+    value.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+fn contains_pattern(value: &str) -> String {
+    format!("%{}%", escape_like(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sort_accepts_only_whitelisted_fields() {
+        assert_eq!(
+            Sort::parse("-created_at").unwrap(),
+            Sort {
+                field: SortField::CreatedAt,
+                descending: true
+            }
+        );
+        assert!(Sort::parse("email; DROP TABLE users").is_err());
+    }
+
+    #[test]
+    fn filters_are_bound_not_interpolated() {
+        let listing = UserListing::from_params(ListUsersParams {
+            name: Some("50%_off'".to_string()),
+            sort: Some("name".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let sql = listing.page_query().into_sql();
+
+        assert!(!sql.contains("50%"));
+        assert!(sql.contains("name LIKE ?"));
+        assert!(sql.contains("ORDER BY name ASC, id ASC"));
+        assert_eq!(contains_pattern("50%_off'"), "%50\\%\\_off'%");
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let params = ListUsersParams {
+            fields: Some("id,password".to_string()),
+            ..Default::default()
+        };
+
+        assert!(UserListing::from_params(params).is_err());
+    }
+}
@@ -1,7 +1,22 @@
-use lambda_rust_sqlite3_efs::{api, db};
+use lambda_rust_sqlite3_efs::{
+    api,
+    config::{ApiMode, Config},
+    db,
+};
 
 #[tokio::main]
 async fn main() {
-    let state = db::bootstrap().await;
-    api::serve_api(state).await;
+    let config = Config::load().unwrap_or_else(|e| panic!("Invalid configuration: {}", e));
+    let access = config.api_access();
+    let state = db::bootstrap(config, access).await;
+
+    match state.config.api_mode {
+        ApiMode::Http => api::serve_api(state).await,
+        #[cfg(feature = "lambda-runtime")]
+        ApiMode::Lambda => lambda_rust_sqlite3_efs::lambda::run_api(state)
+            .await
+            .expect("Lambda runtime failed"),
+        #[cfg(not(feature = "lambda-runtime"))]
+        ApiMode::Lambda => unreachable!("rejected when the configuration is loaded"),
+    }
 }
@@ -1,17 +1,84 @@
+use std::{
+    collections::BTreeMap,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Sqlite};
+use validator::{Validate, ValidationErrors};
+
+use crate::{
+    config::Config,
+    lease::WriterLease,
+    sqs::{DirectPublisher, Publisher},
+    validation::{normalize, not_blank, MAX_EMAIL_LENGTH, MAX_NAME_LENGTH},
+};
 
-#[derive(Clone)]
 pub struct AppState {
     pub pool: Pool<Sqlite>,
+    /// Where writes go; see [`crate::sqs::publisher_from_config`].
+    pub publisher: Box<dyn Publisher>,
+    /// Taken by every transaction that writes to the database.
+    pub lease: Arc<WriterLease>,
+    /// Where the API records idempotency keys and the operations it issued:
+    /// `pool` itself when it can write, else the database at
+    /// `BOOKKEEPING_DATABASE_URL`.
+    pub bookkeeping: Pool<Sqlite>,
+    /// Set once the schema is known to be migrated; see
+    /// [`crate::api::require_schema`].
+    pub schema_ready: AtomicBool,
+    pub config: Config,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+impl AppState {
+    /// State that applies writes directly, as when no queue is configured.
+    pub fn new(pool: Pool<Sqlite>) -> Self {
+        Self::with_config(pool, Config::default())
+    }
+
+    /// Like [`AppState::new`], with the rest of the configuration given.
+    pub fn with_config(pool: Pool<Sqlite>, config: Config) -> Self {
+        let lease = Arc::new(WriterLease::new(config.lease.clone()));
+        AppState {
+            publisher: Box::new(DirectPublisher::new(pool.clone(), lease.clone())),
+            bookkeeping: pool.clone(),
+            pool,
+            lease,
+            schema_ready: AtomicBool::new(true),
+            config,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Validate)]
 pub struct CreateUserRequest {
+    #[validate(
+        length(max = MAX_NAME_LENGTH, message = "must be at most 100 characters"),
+        custom(function = "not_blank")
+    )]
     pub name: String,
+    #[validate(
+        email(message = "must be a valid email address"),
+        length(max = MAX_EMAIL_LENGTH, message = "must be at most 254 characters")
+    )]
     pub email: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Validate)]
+pub struct UpdateUserRequest {
+    #[validate(
+        length(max = MAX_NAME_LENGTH, message = "must be at most 100 characters"),
+        custom(function = "not_blank")
+    )]
+    pub name: Option<String>,
+    #[validate(
+        email(message = "must be a valid email address"),
+        length(max = MAX_EMAIL_LENGTH, message = "must be at most 254 characters")
+    )]
+    pub email: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct CreateUserResponse {
     pub id: String,
@@ -23,21 +90,131 @@ pub struct User {
     pub id: String,
     pub name: String,
     pub email: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Validate)]
 pub struct QueuedUser {
+    #[validate(custom(function = "not_blank"))]
     pub id: String,
+    #[validate(
+        length(max = MAX_NAME_LENGTH, message = "must be at most 100 characters"),
+        custom(function = "not_blank")
+    )]
     pub name: String,
+    #[validate(
+        email(message = "must be a valid email address"),
+        length(max = MAX_EMAIL_LENGTH, message = "must be at most 254 characters")
+    )]
     pub email: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, Validate)]
+pub struct QueuedUserUpdate {
+    #[validate(custom(function = "not_blank"))]
+    pub id: String,
+    #[validate(
+        length(max = MAX_NAME_LENGTH, message = "must be at most 100 characters"),
+        custom(function = "not_blank")
+    )]
+    pub name: Option<String>,
+    #[validate(
+        email(message = "must be a valid email address"),
+        length(max = MAX_EMAIL_LENGTH, message = "must be at most 254 characters")
+    )]
+    pub email: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Validate)]
+pub struct QueuedUserDelete {
+    #[validate(custom(function = "not_blank"))]
+    pub id: String,
+}
+
+/// A write queued for the writer. On the wire it travels inside a
+/// [`MessageEnvelope`](crate::envelope::MessageEnvelope).
+#[derive(Clone, Debug)]
+pub enum QueuedOperation {
+    CreateUser(QueuedUser),
+    UpdateUser(QueuedUserUpdate),
+    DeleteUser(QueuedUserDelete),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum OperationStatus {
+    Pending,
+    Applied,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OperationStatusResponse {
+    pub id: String,
+    pub status: OperationStatus,
+    pub user_id: String,
+    pub operation: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure_reason: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct ListUsersParams {
+    pub limit: Option<u32>,
+    pub cursor: Option<String>,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub email_domain: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub updated_after: Option<DateTime<Utc>>,
+    pub updated_before: Option<DateTime<Utc>>,
+    pub sort: Option<String>,
+    pub fields: Option<String>,
+    /// Streams every matching user as one JSON array instead of a page.
+    pub stream: Option<bool>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ExportUsersParams {
+    pub format: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SearchUsersParams {
+    pub q: String,
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UserHighlight {
+    pub name: String,
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UserSearchHit {
+    #[serde(flatten)]
+    pub user: User,
+    pub score: f64,
+    pub highlight: UserHighlight,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserSearchResult {
+    pub users: Vec<UserSearchHit>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct MultipleUsersResult {
     pub users: Vec<User>,
+    #[serde(default)]
+    pub next_cursor: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct SqsRecord {
     #[serde(rename = "messageId")]
     pub message_id: Option<String>,
@@ -57,12 +234,72 @@ pub struct SqsRecord {
     pub aws_region: Option<String>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BulkRowStatus {
+    Accepted,
+    Rejected,
+}
+
+/// Outcome of one input row of a bulk import; `row` is 1-based and does not
+/// count a CSV header.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BulkRowResult {
+    pub row: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operation_id: Option<String>,
+    pub status: BulkRowStatus,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub errors: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BulkImportResponse {
+    pub accepted: usize,
+    pub rejected: usize,
+    pub results: Vec<BulkRowResult>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SqsEvent {
     #[serde(rename = "Records")]
     pub records: Vec<SqsRecord>,
 }
 
+/// Partial batch response understood by SQS event source mappings configured
+/// with `ReportBatchItemFailures`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SqsBatchResponse {
+    #[serde(rename = "batchItemFailures")]
+    pub batch_item_failures: Vec<SqsBatchItemFailure>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SqsBatchItemFailure {
+    #[serde(rename = "itemIdentifier")]
+    pub item_identifier: String,
+}
+
+impl CreateUserRequest {
+    pub fn normalized(self) -> Self {
+        CreateUserRequest {
+            name: normalize(&self.name),
+            email: normalize(&self.email),
+        }
+    }
+}
+
+impl UpdateUserRequest {
+    pub fn normalized(self) -> Self {
+        UpdateUserRequest {
+            name: self.name.as_deref().map(normalize),
+            email: self.email.as_deref().map(normalize),
+        }
+    }
+}
+
 impl QueuedUser {
     pub fn from_create_request(req: &CreateUserRequest, id: String) -> Self {
         QueuedUser {
@@ -72,3 +309,43 @@ impl QueuedUser {
         }
     }
 }
+
+impl QueuedUserUpdate {
+    pub fn from_update_request(req: &UpdateUserRequest, id: String) -> Self {
+        QueuedUserUpdate {
+            id,
+            name: req.name.clone(),
+            email: req.email.clone(),
+        }
+    }
+}
+
+impl QueuedOperation {
+    pub fn operation_type(&self) -> &'static str {
+        match self {
+            QueuedOperation::CreateUser(_) => "create",
+            QueuedOperation::UpdateUser(_) => "update",
+            QueuedOperation::DeleteUser(_) => "delete",
+        }
+    }
+
+    pub fn entity_type(&self) -> &'static str {
+        "user"
+    }
+
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        match self {
+            QueuedOperation::CreateUser(user) => user.validate(),
+            QueuedOperation::UpdateUser(update) => update.validate(),
+            QueuedOperation::DeleteUser(delete) => delete.validate(),
+        }
+    }
+
+    pub fn user_id(&self) -> &str {
+        match self {
+            QueuedOperation::CreateUser(user) => &user.id,
+            QueuedOperation::UpdateUser(update) => &update.id,
+            QueuedOperation::DeleteUser(delete) => &delete.id,
+        }
+    }
+}
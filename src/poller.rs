@@ -0,0 +1,483 @@
+use std::{collections::HashSet, future::Future, sync::Arc, time::Duration};
+
+use aws_sdk_sqs::{
+    types::{ChangeMessageVisibilityBatchRequestEntry, DeleteMessageBatchRequestEntry, Message},
+    Client as SqsClient,
+};
+use tokio::sync::oneshot;
+
+use crate::{
+    db,
+    models::{AppState, SqsBatchResponse, SqsEvent, SqsRecord},
+    sqs::{self, SqsSettings, MAX_BATCH_ENTRIES},
+    writer,
+};
+
+pub const DEFAULT_WAIT_TIME: Duration = Duration::from_secs(20);
+pub const DEFAULT_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Longest long poll SQS allows.
+const MAX_WAIT_TIME_SECONDS: u64 = 20;
+/// Pause after a failed `ReceiveMessage` before polling again.
+const RECEIVE_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Polling behaviour, part of [`crate::config::Config`] next to [`SqsSettings`]:
+///
+/// - `SQS_WAIT_TIME_SECONDS`: long poll duration, at most 20.
+/// - `SQS_VISIBILITY_TIMEOUT_SECONDS`: how long received messages stay
+///   hidden; extended while a batch is still being applied.
+/// - `SQS_MAX_MESSAGES`: messages per receive, at most 10.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PollerSettings {
+    pub wait_time: Duration,
+    pub visibility_timeout: Duration,
+    pub max_messages: usize,
+}
+
+impl PollerSettings {
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let number = |key: &str, min: u64, max: u64| -> Result<Option<u64>, String> {
+            lookup(key)
+                .map(|value| match value.parse::<u64>() {
+                    Ok(number) if (min..=max).contains(&number) => Ok(number),
+                    _ => Err(format!(
+                        "{} must be an integer from {} to {}, got {:?}",
+                        key, min, max, value
+                    )),
+                })
+                .transpose()
+        };
+
+        Ok(PollerSettings {
+            wait_time: number("SQS_WAIT_TIME_SECONDS", 0, MAX_WAIT_TIME_SECONDS)?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_WAIT_TIME),
+            // SQS caps visibility timeouts at 12 hours.
+            visibility_timeout: number("SQS_VISIBILITY_TIMEOUT_SECONDS", 2, 43_200)?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_VISIBILITY_TIMEOUT),
+            max_messages: number("SQS_MAX_MESSAGES", 1, MAX_BATCH_ENTRIES as u64)?
+                .map(|max| max as usize)
+                .unwrap_or(MAX_BATCH_ENTRIES),
+        })
+    }
+}
+
+/// Runs the writer without the Lambda Web Adapter: long-polls the queue,
+/// applies each batch with [`writer::process_event`], deletes the messages
+/// that were applied and leaves failed ones to be redelivered. On Ctrl+C or
+/// SIGTERM the batch in progress is finished before the pool is closed.
+pub async fn serve_poller(state: Arc<AppState>) {
+    let sqs_settings = state
+        .config
+        .sqs
+        .clone()
+        .expect("SQS_QUEUE_URL is required to poll the queue");
+    let settings = state.config.poller.clone();
+
+    // A long poll must not be cut short by the per-call timeout.
+    let client = sqs::client(&SqsSettings {
+        operation_timeout: sqs_settings
+            .operation_timeout
+            .max(settings.wait_time + Duration::from_secs(5)),
+        ..sqs_settings.clone()
+    })
+    .await;
+    let queue_url = sqs_settings.queue_url;
+
+    tracing::info!("Writer polling {}", queue_url);
+
+    poll(
+        &state,
+        &client,
+        &queue_url,
+        &settings,
+        db::wait_for_signal(),
+    )
+    .await;
+
+    state.lease.release(&state.pool).await;
+    state.pool.close().await;
+    println!("signal received, writer stopped polling");
+}
+
+/// Receives and applies batches until `shutdown` completes. A batch that is
+/// being applied is finished first.
+async fn poll(
+    state: &AppState,
+    client: &SqsClient,
+    queue_url: &str,
+    settings: &PollerSettings,
+    shutdown: impl Future<Output = ()>,
+) {
+    tokio::pin!(shutdown);
+
+    loop {
+        let receive = client
+            .receive_message()
+            .queue_url(queue_url)
+            .max_number_of_messages(settings.max_messages as i32)
+            .wait_time_seconds(settings.wait_time.as_secs() as i32)
+            .visibility_timeout(settings.visibility_timeout.as_secs() as i32)
+            .send();
+
+        let output = tokio::select! {
+            _ = &mut shutdown => break,
+            output = receive => output,
+        };
+
+        let messages = match output {
+            Ok(output) => output.messages().to_vec(),
+            Err(e) => {
+                tracing::error!("Failed to receive messages from SQS: {}", e);
+                tokio::select! {
+                    _ = &mut shutdown => break,
+                    _ = tokio::time::sleep(RECEIVE_BACKOFF) => continue,
+                }
+            }
+        };
+        if messages.is_empty() {
+            continue;
+        }
+
+        process_messages(state, client, queue_url, settings, &messages).await;
+    }
+}
+
+async fn process_messages(
+    state: &AppState,
+    client: &SqsClient,
+    queue_url: &str,
+    settings: &PollerSettings,
+    messages: &[Message],
+) {
+    let event = SqsEvent {
+        records: messages.iter().map(to_record).collect(),
+    };
+
+    let (done, heartbeat_done) = oneshot::channel();
+    let heartbeat = tokio::spawn(extend_visibility(
+        client.clone(),
+        queue_url.to_string(),
+        receipts(&event.records),
+        settings.visibility_timeout,
+        heartbeat_done,
+    ));
+
+    let response = writer::process_event(&state.pool, &state.lease, &event).await;
+
+    let _ = done.send(());
+    let _ = heartbeat.await;
+
+    let completed = completed_receipts(&event.records, &response);
+    if !response.batch_item_failures.is_empty() {
+        tracing::warn!(
+            "{} of {} messages failed and will be redelivered",
+            response.batch_item_failures.len(),
+            event.records.len()
+        );
+    }
+    delete_messages(client, queue_url, &completed).await;
+}
+
+fn to_record(message: &Message) -> SqsRecord {
+    SqsRecord {
+        message_id: message.message_id().map(str::to_string),
+        receipt_handle: message.receipt_handle().map(str::to_string),
+        body: message.body().map(str::to_string),
+        md5_of_body: message.md5_of_body().map(str::to_string),
+        event_source: Some("aws:sqs".to_string()),
+        ..Default::default()
+    }
+}
+
+fn receipts(records: &[SqsRecord]) -> Vec<(String, String)> {
+    records
+        .iter()
+        .filter_map(|record| Some((record.message_id.clone()?, record.receipt_handle.clone()?)))
+        .collect()
+}
+
+/// `(message id, receipt handle)` of every record not reported as failed.
+fn completed_receipts(records: &[SqsRecord], response: &SqsBatchResponse) -> Vec<(String, String)> {
+    let failed: HashSet<&str> = response
+        .batch_item_failures
+        .iter()
+        .map(|failure| failure.item_identifier.as_str())
+        .collect();
+
+    receipts(records)
+        .into_iter()
+        .filter(|(id, _)| !failed.contains(id.as_str()))
+        .collect()
+}
+
+/// Keeps the batch hidden from other consumers until `done` fires, renewing
+/// the visibility timeout halfway through each period.
+async fn extend_visibility(
+    client: SqsClient,
+    queue_url: String,
+    receipts: Vec<(String, String)>,
+    visibility_timeout: Duration,
+    mut done: oneshot::Receiver<()>,
+) {
+    let mut interval = tokio::time::interval(visibility_timeout / 2);
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = &mut done => return,
+            _ = interval.tick() => {}
+        }
+
+        tracing::info!("Extending visibility of {} slow messages", receipts.len());
+        for chunk in receipts.chunks(MAX_BATCH_ENTRIES) {
+            let entries = chunk
+                .iter()
+                .map(|(id, receipt)| {
+                    ChangeMessageVisibilityBatchRequestEntry::builder()
+                        .id(id)
+                        .receipt_handle(receipt)
+                        .visibility_timeout(visibility_timeout.as_secs() as i32)
+                        .build()
+                })
+                .collect::<Result<Vec<_>, _>>();
+
+            let result = match entries {
+                Ok(entries) => client
+                    .change_message_visibility_batch()
+                    .queue_url(&queue_url)
+                    .set_entries(Some(entries))
+                    .send()
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(e) = result {
+                tracing::warn!("Failed to extend message visibility: {}", e);
+            }
+        }
+    }
+}
+
+async fn delete_messages(client: &SqsClient, queue_url: &str, receipts: &[(String, String)]) {
+    for chunk in receipts.chunks(MAX_BATCH_ENTRIES) {
+        let entries = chunk
+            .iter()
+            .map(|(id, receipt)| {
+                DeleteMessageBatchRequestEntry::builder()
+                    .id(id)
+                    .receipt_handle(receipt)
+                    .build()
+            })
+            .collect::<Result<Vec<_>, _>>();
+
+        let result = match entries {
+            Ok(entries) => client
+                .delete_message_batch()
+                .queue_url(queue_url)
+                .set_entries(Some(entries))
+                .send()
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+
+        // Undeleted messages come back and are skipped as already processed.
+        match result {
+            Ok(output) => {
+                for entry in output.failed() {
+                    tracing::warn!("Failed to delete message {}: {}", entry.id(), entry.code());
+                }
+            }
+            Err(e) => tracing::warn!("Failed to delete messages: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::SqsBatchItemFailure;
+    use aws_sdk_sqs::config::{BehaviorVersion, Credentials, Region};
+    use axum::{
+        body::Bytes,
+        extract::State,
+        http::{header, HeaderMap, StatusCode},
+        response::{IntoResponse, Response},
+        routing::post,
+        Router,
+    };
+    use serde_json::{json, Value};
+    use sqlx::SqlitePool;
+    use std::sync::Mutex;
+    use tokio::sync::Notify;
+
+    /// Just enough of the SQS JSON protocol for the poller: the queued
+    /// messages are delivered once, later receives come back empty.
+    #[derive(Default)]
+    struct MockQueue {
+        messages: Mutex<Vec<Value>>,
+        extended: Mutex<Vec<String>>,
+        deleted: Mutex<Vec<String>>,
+        extended_notify: Notify,
+        deleted_notify: Notify,
+    }
+
+    async fn sqs_api(
+        State(queue): State<Arc<MockQueue>>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Response {
+        let request: Value = serde_json::from_slice(&body).unwrap();
+        let entries = |handled: &Mutex<Vec<String>>, notify: &Notify| {
+            let entries = request["Entries"].as_array().cloned().unwrap_or_default();
+            let mut handled = handled.lock().unwrap();
+            for entry in &entries {
+                handled.push(entry["ReceiptHandle"].as_str().unwrap().to_string());
+            }
+            notify.notify_one();
+            let successful: Vec<Value> = entries
+                .iter()
+                .map(|entry| json!({ "Id": entry["Id"] }))
+                .collect();
+            json!({ "Successful": successful, "Failed": [] })
+        };
+
+        let target = headers
+            .get("x-amz-target")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let response = match target {
+            "AmazonSQS.ReceiveMessage" => {
+                let messages = std::mem::take(&mut *queue.messages.lock().unwrap());
+                if messages.is_empty() {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                json!({ "Messages": messages })
+            }
+            "AmazonSQS.ChangeMessageVisibilityBatch" => {
+                entries(&queue.extended, &queue.extended_notify)
+            }
+            "AmazonSQS.DeleteMessageBatch" => entries(&queue.deleted, &queue.deleted_notify),
+            other => return (StatusCode::BAD_REQUEST, other.to_string()).into_response(),
+        };
+
+        (
+            [(header::CONTENT_TYPE, "application/x-amz-json-1.0")],
+            response.to_string(),
+        )
+            .into_response()
+    }
+
+    async fn mock_sqs(queue: Arc<MockQueue>) -> SqsClient {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = Router::new().route("/", post(sqs_api)).with_state(queue);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = aws_sdk_sqs::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .endpoint_url(format!("http://{}", address))
+            .build();
+        SqsClient::from_conf(config)
+    }
+
+    #[sqlx::test]
+    async fn slow_batches_are_kept_hidden_and_deleted_once_applied(pool: SqlitePool) {
+        let queue = Arc::new(MockQueue::default());
+        queue.messages.lock().unwrap().extend([
+            json!({
+                "MessageId": "m1",
+                "ReceiptHandle": "r1",
+                "Body": r#"{"id":"poll-001","name":"polled","email":"polled@example.com"}"#,
+            }),
+            json!({ "MessageId": "m2", "ReceiptHandle": "r2", "Body": "not json" }),
+        ]);
+        let client = mock_sqs(queue.clone()).await;
+
+        let state = Arc::new(AppState::new(pool));
+        let settings = PollerSettings {
+            wait_time: Duration::from_secs(1),
+            visibility_timeout: Duration::from_secs(2),
+            max_messages: MAX_BATCH_ENTRIES,
+        };
+
+        // Holding the write lock keeps the batch waiting until its
+        // visibility has been extended.
+        let mut blocker = state.pool.acquire().await.unwrap();
+        sqlx::query("BEGIN IMMEDIATE")
+            .execute(&mut *blocker)
+            .await
+            .unwrap();
+
+        tokio::spawn({
+            let queue = queue.clone();
+            async move {
+                queue.extended_notify.notified().await;
+                sqlx::query("COMMIT").execute(&mut *blocker).await.unwrap();
+            }
+        });
+
+        let shutdown = queue.deleted_notify.notified();
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            poll(&state, &client, "http://queue", &settings, shutdown),
+        )
+        .await
+        .unwrap();
+
+        let extended = queue.extended.lock().unwrap().clone();
+        assert!(extended.contains(&"r1".to_string()));
+        assert!(extended.contains(&"r2".to_string()));
+        assert_eq!(*queue.deleted.lock().unwrap(), vec!["r1".to_string()]);
+
+        let name: String = sqlx::query_scalar("SELECT name FROM users WHERE id = 'poll-001'")
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+        assert_eq!(name, "polled");
+    }
+
+    #[test]
+    fn only_applied_messages_are_deleted() {
+        let messages = [
+            Message::builder()
+                .message_id("m1")
+                .receipt_handle("r1")
+                .body("{}")
+                .build(),
+            Message::builder()
+                .message_id("m2")
+                .receipt_handle("r2")
+                .body("{}")
+                .build(),
+        ];
+        let records: Vec<SqsRecord> = messages.iter().map(to_record).collect();
+        let response = SqsBatchResponse {
+            batch_item_failures: vec![SqsBatchItemFailure {
+                item_identifier: "m2".to_string(),
+            }],
+        };
+
+        assert_eq!(
+            completed_receipts(&records, &response),
+            vec![("m1".to_string(), "r1".to_string())]
+        );
+    }
+
+    #[test]
+    fn settings_are_bounded() {
+        let settings = PollerSettings::from_lookup(|_| None).unwrap();
+        assert_eq!(settings.wait_time, DEFAULT_WAIT_TIME);
+        assert_eq!(settings.max_messages, MAX_BATCH_ENTRIES);
+
+        let too_long = PollerSettings::from_lookup(|key| {
+            (key == "SQS_WAIT_TIME_SECONDS").then(|| "30".to_string())
+        });
+        assert!(too_long.is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use sqlx::{FromRow, Pool, Row, Sqlite};
+
+use crate::models::{User, UserHighlight, UserSearchHit};
+
+/// Upper bound on the terms taken from a query, to keep FTS queries cheap.
+const MAX_TERMS: usize = 8;
+
+pub const HIGHLIGHT_START: &str = "<mark>";
+pub const HIGHLIGHT_END: &str = "</mark>";
+
+// highlight() marks matches with control characters, which are replaced by
+// the tags once the rest of the text has been HTML-escaped.
+const MARK_START: &str = "\u{2}";
+const MARK_END: &str = "\u{3}";
+
+// bm25 weights follow the column order of users_fts: name, email.
+const SEARCH_USERS: &str =
+    "SELECT users.id, users.name, users.email, users.created_at, users.updated_at,
+        -bm25(users_fts, 10.0, 5.0) AS score,
+        highlight(users_fts, 0, $1, $2) AS name_highlight,
+        highlight(users_fts, 1, $1, $2) AS email_highlight
+    FROM users_fts
+    JOIN users ON users.seq = users_fts.rowid
+    WHERE users_fts MATCH $3
+    ORDER BY score DESC, users.id
+    LIMIT $4";
+
+/// Turns free text into an FTS5 expression where every term must match as a
+/// prefix, e.g. `ali exa` becomes `"ali"* "exa"*`. Quoting each term keeps
+/// FTS5 operators in the input from being interpreted.
+///
+/// Matching is prefix-only: case and diacritics are ignored, but a misspelt
+/// term or one taken from the middle of a word finds nothing.
+pub fn match_expression(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .take(MAX_TERMS)
+        .map(|term| format!("\"{}\"*", term))
+        .collect();
+
+    if terms.is_empty() {
+        return None;
+    }
+
+    Some(terms.join(" "))
+}
+
+pub async fn search_users(
+    pool: &Pool<Sqlite>,
+    expression: &str,
+    limit: u32,
+) -> Result<Vec<UserSearchHit>, sqlx::Error> {
+    let rows = sqlx::query(SEARCH_USERS)
+        .bind(MARK_START)
+        .bind(MARK_END)
+        .bind(expression)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(UserSearchHit {
+                user: User::from_row(row)?,
+                score: row.try_get("score")?,
+                highlight: UserHighlight {
+                    name: escape_highlight(row.try_get("name_highlight")?),
+                    email: escape_highlight(row.try_get("email_highlight")?),
+                },
+            })
+        })
+        .collect()
+}
+
+/// HTML-escapes a highlighted value, then turns the match markers into
+/// [`HIGHLIGHT_START`] and [`HIGHLIGHT_END`].
+fn escape_highlight(text: String) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+        .replace(MARK_START, HIGHLIGHT_START)
+        .replace(MARK_END, HIGHLIGHT_END)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_expression_quotes_prefix_terms() {
+        assert_eq!(
+            match_expression("ali example.com").as_deref(),
+            Some("\"ali\"* \"example\"* \"com\"*")
+        );
+        assert_eq!(
+            match_expression("name:x OR \"y").as_deref(),
+            Some("\"name\"* \"x\"* \"OR\"* \"y\"*")
+        );
+        assert_eq!(match_expression(" -- "), None);
+    }
+
+    #[test]
+    fn highlights_are_html_escaped() {
+        assert_eq!(
+            escape_highlight("\u{2}Tom\u{3} <script>&\"'".to_string()),
+            "<mark>Tom</mark> &lt;script&gt;&amp;&quot;&#39;"
+        );
+    }
+
+    #[sqlx::test]
+    async fn search_ranks_name_matches_first(pool: sqlx::SqlitePool) {
+        for (id, name, email) in [
+            ("s1", "Marta Lopez", "marta@example.com"),
+            ("s2", "John Smith", "jsmith@martas-shop.test"),
+            ("s3", "Ana Souza", "ana@example.com"),
+        ] {
+            sqlx::query("INSERT INTO users (id, name, email) VALUES ($1, $2, $3)")
+                .bind(id)
+                .bind(name)
+                .bind(email)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let hits = search_users(&pool, &match_expression("mart").unwrap(), 10)
+            .await
+            .unwrap();
+
+        let ids: Vec<&str> = hits.iter().map(|hit| hit.user.id.as_str()).collect();
+        assert_eq!(ids, vec!["s1", "s2"]);
+        assert_eq!(hits[0].highlight.name, "<mark>Marta</mark> Lopez");
+
+        sqlx::query("DELETE FROM users WHERE id = 's1'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE users SET name = 'Ana Martins' WHERE id = 's3'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let hits = search_users(&pool, &match_expression("mart").unwrap(), 10)
+            .await
+            .unwrap();
+        let ids: Vec<&str> = hits.iter().map(|hit| hit.user.id.as_str()).collect();
+        assert_eq!(ids, vec!["s3", "s2"]);
+    }
+}
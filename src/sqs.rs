@@ -1,18 +1,507 @@
-use aws_sdk_sqs::Client as SqsClient;
-
-pub async fn publish_message(
-    queue_url: &str,
-    message_body: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let config = aws_config::load_from_env().await;
-    let client = SqsClient::new(&config);
-
-    client
-        .send_message()
-        .queue_url(queue_url)
-        .message_body(message_body)
-        .send()
-        .await?;
-
-    Ok(())
+use std::{fmt, path::PathBuf, str::FromStr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use aws_config::{retry::RetryConfig, timeout::TimeoutConfig, BehaviorVersion};
+use aws_sdk_sqs::{types::SendMessageBatchRequestEntry, Client as SqsClient};
+use sqlx::{Pool, Sqlite};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::{mpsc, Mutex},
+};
+
+use crate::{
+    config::Config,
+    db::DatabaseAccess,
+    envelope,
+    id::generate_xid_string,
+    lease::WriterLease,
+    models::{SqsEvent, SqsRecord},
+    writer::{self, ApplyError},
+};
+
+/// Largest number of entries SQS accepts in one `SendMessageBatch` call.
+pub const MAX_BATCH_ENTRIES: usize = 10;
+
+pub const DEFAULT_SPOOL_PATH: &str = "./outbox.ndjson";
+
+/// Messages buffered by the in-memory publisher before `publish` waits.
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug)]
+pub enum PublishError {
+    /// The operation was applied right away and the database refused it.
+    Rejected(ApplyError),
+    /// The message could not be handed over; publishing again may work.
+    Failed(String),
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublishError::Rejected(e) => write!(f, "{}", e),
+            PublishError::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// Hands encoded messages over to the writer.
+#[async_trait]
+pub trait Publisher: Send + Sync {
+    async fn publish(&self, message_body: &str) -> Result<(), PublishError>;
+
+    /// Publishes `(entry id, body)` pairs and returns the entries that were
+    /// not published, with the reason.
+    async fn publish_batch(&self, messages: &[(String, String)]) -> Vec<(String, PublishError)> {
+        let mut failed = Vec::new();
+        for (id, body) in messages {
+            if let Err(e) = self.publish(body).await {
+                failed.push((id.clone(), e));
+            }
+        }
+
+        failed
+    }
+}
+
+/// Where the API hands writes over, from `PUBLISHER`. Without it, SQS is
+/// used when `SQS_QUEUE_URL` is set and writes are applied directly
+/// otherwise.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PublisherKind {
+    /// Publish to `SQS_QUEUE_URL`, see [`SqsSettings`].
+    Sqs,
+    /// Feed an in-process writer through an in-memory channel.
+    Channel,
+    /// Append to the file at `PUBLISHER_SPOOL_PATH`.
+    Spool,
+    /// Apply writes in the request.
+    Direct,
+}
+
+impl FromStr for PublisherKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "sqs" => Ok(PublisherKind::Sqs),
+            "channel" => Ok(PublisherKind::Channel),
+            "spool" => Ok(PublisherKind::Spool),
+            "direct" => Ok(PublisherKind::Direct),
+            other => Err(format!(
+                "PUBLISHER must be one of sqs, channel, spool or direct, got {:?}",
+                other
+            )),
+        }
+    }
+}
+
+/// Builds the configured publisher. The publishers that write to the
+/// database are refused when it was opened read-only.
+pub async fn publisher_from_config(
+    config: &Config,
+    pool: &Pool<Sqlite>,
+    lease: &Arc<WriterLease>,
+    access: DatabaseAccess,
+) -> Result<Box<dyn Publisher>, String> {
+    let kind = config.publisher;
+
+    if access == DatabaseAccess::ReadOnly
+        && matches!(kind, PublisherKind::Channel | PublisherKind::Direct)
+    {
+        return Err(format!(
+            "the {:?} publisher writes to the database, which is opened read-only; set \
+             SQS_QUEUE_URL, use PUBLISHER=spool, or set API_LOCAL_WRITES=true for local development",
+            kind
+        ));
+    }
+
+    let publisher: Box<dyn Publisher> = match kind {
+        PublisherKind::Sqs => {
+            let settings = config
+                .sqs
+                .as_ref()
+                .ok_or("PUBLISHER=sqs requires SQS_QUEUE_URL")?;
+            Box::new(SqsPublisher::new(settings).await)
+        }
+        PublisherKind::Channel => Box::new(ChannelPublisher::spawn(pool.clone(), lease.clone())),
+        PublisherKind::Spool => Box::new(
+            SpoolPublisher::open(&config.spool_path)
+                .await
+                .map_err(|e| e.to_string())?,
+        ),
+        PublisherKind::Direct => Box::new(DirectPublisher::new(pool.clone(), lease.clone())),
+    };
+
+    tracing::info!("Publishing writes with the {:?} publisher", kind);
+
+    Ok(publisher)
+}
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+pub const DEFAULT_OPERATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How to reach the queue, part of [`Config`]:
+///
+/// - `SQS_QUEUE_URL`: queue to publish to; writes are applied directly
+///   when unset.
+/// - `SQS_ENDPOINT_URL`: endpoint of an SQS-compatible stand-in.
+/// - `SQS_MAX_ATTEMPTS`: attempts per call, including the first one.
+/// - `SQS_CONNECT_TIMEOUT_MS`, `SQS_OPERATION_TIMEOUT_MS`: time allowed to
+///   connect, and for a whole call including retries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SqsSettings {
+    pub queue_url: String,
+    pub endpoint_url: Option<String>,
+    pub max_attempts: u32,
+    pub connect_timeout: Duration,
+    pub operation_timeout: Duration,
+}
+
+impl SqsSettings {
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Option<Self>, String> {
+        let Some(queue_url) = lookup("SQS_QUEUE_URL") else {
+            return Ok(None);
+        };
+
+        let number = |key: &str| -> Result<Option<u64>, String> {
+            lookup(key)
+                .map(|value| match value.parse::<u64>() {
+                    Ok(number) if number > 0 => Ok(number),
+                    _ => Err(format!(
+                        "{} must be a positive integer, got {:?}",
+                        key, value
+                    )),
+                })
+                .transpose()
+        };
+
+        Ok(Some(SqsSettings {
+            queue_url,
+            endpoint_url: lookup("SQS_ENDPOINT_URL"),
+            max_attempts: number("SQS_MAX_ATTEMPTS")?
+                .map(|attempts| attempts.min(u32::MAX as u64) as u32)
+                .unwrap_or(DEFAULT_MAX_ATTEMPTS),
+            connect_timeout: number("SQS_CONNECT_TIMEOUT_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            operation_timeout: number("SQS_OPERATION_TIMEOUT_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_OPERATION_TIMEOUT),
+        }))
+    }
+}
+
+/// Builds a client with the endpoint, retry and timeout settings applied.
+pub async fn client(settings: &SqsSettings) -> SqsClient {
+    let mut loader = aws_config::defaults(BehaviorVersion::latest())
+        .retry_config(RetryConfig::standard().with_max_attempts(settings.max_attempts))
+        .timeout_config(
+            TimeoutConfig::builder()
+                .connect_timeout(settings.connect_timeout)
+                .operation_timeout(settings.operation_timeout)
+                .build(),
+        );
+    if let Some(endpoint_url) = &settings.endpoint_url {
+        loader = loader.endpoint_url(endpoint_url);
+    }
+
+    SqsClient::new(&loader.load().await)
+}
+
+/// An SQS client for one queue. AWS config and credentials are resolved once
+/// when it is built; clones share the same client.
+#[derive(Clone, Debug)]
+pub struct SqsPublisher {
+    client: SqsClient,
+    queue_url: String,
+}
+
+impl SqsPublisher {
+    pub async fn new(settings: &SqsSettings) -> Self {
+        SqsPublisher {
+            client: client(settings).await,
+            queue_url: settings.queue_url.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Publisher for SqsPublisher {
+    async fn publish(&self, message_body: &str) -> Result<(), PublishError> {
+        self.client
+            .send_message()
+            .queue_url(&self.queue_url)
+            .message_body(message_body)
+            .send()
+            .await
+            .map_err(|e| PublishError::Failed(format!("failed to publish to SQS: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Sends `SendMessageBatch` requests of [`MAX_BATCH_ENTRIES`]. A request
+    /// that fails counts every entry in it as failed.
+    async fn publish_batch(&self, messages: &[(String, String)]) -> Vec<(String, PublishError)> {
+        let mut failed = Vec::new();
+
+        for chunk in messages.chunks(MAX_BATCH_ENTRIES) {
+            let entries = chunk
+                .iter()
+                .map(|(id, body)| {
+                    SendMessageBatchRequestEntry::builder()
+                        .id(id)
+                        .message_body(body)
+                        .build()
+                })
+                .collect::<Result<Vec<_>, _>>();
+
+            let result = match entries {
+                Ok(entries) => self
+                    .client
+                    .send_message_batch()
+                    .queue_url(&self.queue_url)
+                    .set_entries(Some(entries))
+                    .send()
+                    .await
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+
+            match result {
+                Ok(output) => {
+                    for entry in output.failed() {
+                        tracing::error!(
+                            "Failed to publish batch entry {} to SQS: {} {}",
+                            entry.id(),
+                            entry.code(),
+                            entry.message().unwrap_or_default()
+                        );
+                        failed.push((
+                            entry.id().to_string(),
+                            PublishError::Failed(entry.code().to_string()),
+                        ));
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("Failed to publish batch to SQS: {}", e);
+                    failed.extend(
+                        chunk
+                            .iter()
+                            .map(|(id, _)| (id.clone(), PublishError::Failed(e.clone()))),
+                    );
+                }
+            }
+        }
+
+        failed
+    }
+}
+
+/// Feeds messages to a writer task in this process, through the same
+/// [`writer::process_event`] path as queued events. Messages still in the
+/// channel are lost when the process exits.
+pub struct ChannelPublisher {
+    sender: mpsc::Sender<SqsRecord>,
+}
+
+impl ChannelPublisher {
+    pub fn spawn(pool: Pool<Sqlite>, lease: Arc<WriterLease>) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<SqsRecord>(CHANNEL_CAPACITY);
+
+        tokio::spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                let mut records = vec![record];
+                while records.len() < MAX_BATCH_ENTRIES {
+                    match receiver.try_recv() {
+                        Ok(record) => records.push(record),
+                        Err(_) => break,
+                    }
+                }
+
+                let event = SqsEvent { records };
+                let response = writer::process_event(&pool, &lease, &event).await;
+                for failure in response.batch_item_failures {
+                    tracing::error!(
+                        "In-memory writer failed to apply message {}",
+                        failure.item_identifier
+                    );
+                }
+            }
+        });
+
+        ChannelPublisher { sender }
+    }
+}
+
+#[async_trait]
+impl Publisher for ChannelPublisher {
+    async fn publish(&self, message_body: &str) -> Result<(), PublishError> {
+        self.sender
+            .send(record(message_body))
+            .await
+            .map_err(|_| PublishError::Failed("in-memory writer has stopped".to_string()))
+    }
+}
+
+/// Appends each message as one line of SQS record JSON, so a spool file can
+/// later be replayed through the writer's `POST /events`.
+pub struct SpoolPublisher {
+    file: Mutex<File>,
+}
+
+impl SpoolPublisher {
+    pub async fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.into())
+            .await?;
+
+        Ok(SpoolPublisher {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+#[async_trait]
+impl Publisher for SpoolPublisher {
+    async fn publish(&self, message_body: &str) -> Result<(), PublishError> {
+        let mut line = serde_json::to_vec(&record(message_body))
+            .map_err(|e| PublishError::Failed(e.to_string()))?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().await;
+        file.write_all(&line)
+            .await
+            .map_err(|e| PublishError::Failed(format!("failed to write spool file: {}", e)))?;
+        file.sync_data()
+            .await
+            .map_err(|e| PublishError::Failed(format!("failed to sync spool file: {}", e)))
+    }
+}
+
+/// Applies each message to the database before returning, so clients see
+/// conflicts right away. Used when no queue is configured.
+pub struct DirectPublisher {
+    pool: Pool<Sqlite>,
+    lease: Arc<WriterLease>,
+}
+
+impl DirectPublisher {
+    pub fn new(pool: Pool<Sqlite>, lease: Arc<WriterLease>) -> Self {
+        DirectPublisher { pool, lease }
+    }
+}
+
+#[async_trait]
+impl Publisher for DirectPublisher {
+    async fn publish(&self, message_body: &str) -> Result<(), PublishError> {
+        let message = envelope::decode_message(message_body)
+            .map_err(|e| PublishError::Failed(e.to_string()))?;
+        let operation_id = message.operation_id.unwrap_or_else(generate_xid_string);
+
+        writer::apply_direct(&self.pool, &self.lease, &operation_id, &message.operation)
+            .await
+            .map_err(PublishError::Rejected)
+    }
+}
+
+fn record(message_body: &str) -> SqsRecord {
+    SqsRecord {
+        message_id: Some(generate_xid_string()),
+        body: Some(message_body.to_string()),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn settings(vars: &[(&str, &str)]) -> Result<Option<SqsSettings>, String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        SqsSettings::from_lookup(|key| vars.get(key).cloned())
+    }
+
+    fn create_message(id: &str) -> String {
+        envelope::encode_message(
+            "op-chan1",
+            &crate::models::QueuedOperation::CreateUser(crate::models::QueuedUser {
+                id: id.to_string(),
+                name: "Queued".to_string(),
+                email: format!("{}@example.com", id),
+            }),
+        )
+        .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn channel_publisher_feeds_the_writer(pool: sqlx::SqlitePool) {
+        let lease = Arc::new(WriterLease::new(Default::default()));
+        let publisher = ChannelPublisher::spawn(pool.clone(), lease);
+        publisher.publish(&create_message("chan1")).await.unwrap();
+
+        let mut found = None;
+        for _ in 0..100 {
+            found = sqlx::query_scalar::<_, String>("SELECT name FROM users WHERE id = 'chan1'")
+                .fetch_optional(&pool)
+                .await
+                .unwrap();
+            if found.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(found.as_deref(), Some("Queued"));
+    }
+
+    #[tokio::test]
+    async fn spool_publisher_appends_sqs_records() {
+        let path = std::env::temp_dir().join(format!("spool-{}.ndjson", generate_xid_string()));
+        let publisher = SpoolPublisher::open(&path).await.unwrap();
+
+        let messages = vec![
+            ("0".to_string(), create_message("sp1")),
+            ("1".to_string(), create_message("sp2")),
+        ];
+        assert!(publisher.publish_batch(&messages).await.is_empty());
+
+        let spooled = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let records: Vec<SqsRecord> = spooled
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].body.as_deref(), Some(messages[1].1.as_str()));
+        assert!(records[0].message_id.is_some());
+    }
+
+    #[test]
+    fn settings_default_and_validate() {
+        assert_eq!(settings(&[]), Ok(None));
+
+        let parsed = settings(&[
+            ("SQS_QUEUE_URL", "http://localhost:9324/queue/users"),
+            ("SQS_ENDPOINT_URL", "http://localhost:9324"),
+            ("SQS_OPERATION_TIMEOUT_MS", "2500"),
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            parsed.endpoint_url.as_deref(),
+            Some("http://localhost:9324")
+        );
+        assert_eq!(parsed.max_attempts, DEFAULT_MAX_ATTEMPTS);
+        assert_eq!(parsed.operation_timeout, Duration::from_millis(2500));
+
+        assert!(settings(&[("SQS_QUEUE_URL", "q"), ("SQS_MAX_ATTEMPTS", "0")]).is_err());
+    }
 }
@@ -0,0 +1,235 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use futures::{channel::mpsc, SinkExt, TryStreamExt};
+use serde_json::Value;
+use sqlx::{FromRow, Pool, Sqlite};
+
+use crate::{listing::UserListing, models::User};
+
+pub const NDJSON: &str = "application/x-ndjson";
+pub const CSV: &str = "text/csv; charset=utf-8";
+
+/// Rows buffered between the database task and the response body. Keeps
+/// memory flat when the client reads slower than SQLite produces rows.
+const CHANNEL_CAPACITY: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamFormat {
+    /// One JSON object per line.
+    NdJson,
+    /// A single JSON array, written one element at a time.
+    JsonArray,
+    /// RFC 4180 CSV with a header row of the selected fields.
+    Csv,
+}
+
+impl StreamFormat {
+    /// Picks NDJSON when the client accepts it, otherwise a JSON array when
+    /// streaming was asked for explicitly.
+    pub fn negotiate(headers: &HeaderMap, stream: bool) -> Option<Self> {
+        let accepts_ndjson = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|media| media.split(';').next().unwrap_or_default().trim() == NDJSON);
+
+        match (accepts_ndjson, stream) {
+            (true, _) => Some(StreamFormat::NdJson),
+            (false, true) => Some(StreamFormat::JsonArray),
+            (false, false) => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            StreamFormat::NdJson => NDJSON,
+            StreamFormat::JsonArray => "application/json",
+            StreamFormat::Csv => CSV,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            StreamFormat::NdJson => "ndjson",
+            StreamFormat::JsonArray => "json",
+            StreamFormat::Csv => "csv",
+        }
+    }
+
+    fn header(&self, fields: &[&str]) -> String {
+        match self {
+            StreamFormat::NdJson => String::new(),
+            StreamFormat::JsonArray => "[".to_string(),
+            StreamFormat::Csv => csv_record(fields.iter().copied()),
+        }
+    }
+
+    fn row(&self, index: usize, user: &Value, fields: &[&str]) -> String {
+        match self {
+            StreamFormat::NdJson => format!("{}\n", user),
+            StreamFormat::JsonArray if index == 0 => user.to_string(),
+            StreamFormat::JsonArray => format!(",{}", user),
+            StreamFormat::Csv => {
+                let cells: Vec<String> =
+                    fields.iter().map(|field| csv_cell(&user[*field])).collect();
+                csv_record(cells.iter().map(String::as_str))
+            }
+        }
+    }
+
+    fn footer(&self) -> &'static str {
+        match self {
+            StreamFormat::NdJson | StreamFormat::Csv => "",
+            StreamFormat::JsonArray => "]",
+        }
+    }
+}
+
+/// Renders a value as a CSV cell. Text that a spreadsheet would read as a
+/// formula is prefixed with `'` so an exported name cannot run one.
+fn csv_cell(value: &Value) -> String {
+    let text = match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    };
+
+    if text.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", text)
+    } else {
+        text
+    }
+}
+
+/// Writes one RFC 4180 record, quoting cells only where needed.
+fn csv_record<'a>(cells: impl IntoIterator<Item = &'a str>) -> String {
+    let mut writer = csv::WriterBuilder::new()
+        .terminator(csv::Terminator::CRLF)
+        .from_writer(Vec::new());
+    writer
+        .write_record(cells)
+        .expect("writing to memory cannot fail");
+    let bytes = writer.into_inner().expect("writing to memory cannot fail");
+
+    String::from_utf8(bytes).expect("cells are valid UTF-8")
+}
+
+/// Streams every user matching `listing` straight from the row cursor into
+/// the response body, so the result set is never held in memory. Rows are
+/// read inside one transaction, so the output is a consistent snapshot even
+/// while the writer keeps committing; in WAL mode the reader does not block
+/// it.
+///
+/// The status line is sent before the first row is read; a database error
+/// after that point aborts the body instead of producing an error response.
+pub fn stream_users(pool: Pool<Sqlite>, listing: UserListing, format: StreamFormat) -> Response {
+    let (mut sender, receiver) = mpsc::channel::<Result<Bytes, sqlx::Error>>(CHANNEL_CAPACITY);
+
+    tokio::spawn(async move {
+        if let Err(e) = write_users(&pool, &listing, format, &mut sender).await {
+            tracing::error!("Streaming users failed: {}", e);
+            let _ = sender.send(Err(e)).await;
+        }
+    });
+
+    let mut response = Body::from_stream(receiver).into_response();
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+
+    response
+}
+
+async fn write_users(
+    pool: &Pool<Sqlite>,
+    listing: &UserListing,
+    format: StreamFormat,
+    sender: &mut mpsc::Sender<Result<Bytes, sqlx::Error>>,
+) -> Result<(), sqlx::Error> {
+    let fields = listing.selected_fields();
+    let mut tx = pool.begin().await?;
+    let mut query = listing.stream_query();
+    let mut rows = query.build().fetch(&mut *tx);
+
+    // A closed channel means the client went away; stop reading rows.
+    if sender
+        .send(Ok(Bytes::from(format.header(&fields))))
+        .await
+        .is_err()
+    {
+        return Ok(());
+    }
+
+    let mut index = 0;
+    while let Some(row) = rows.try_next().await? {
+        let mut user = serde_json::to_value(User::from_row(&row)?)
+            .map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+        listing.project(std::slice::from_mut(&mut user));
+
+        if sender
+            .send(Ok(Bytes::from(format.row(index, &user, &fields))))
+            .await
+            .is_err()
+        {
+            return Ok(());
+        }
+        index += 1;
+    }
+
+    let _ = sender.send(Ok(Bytes::from(format.footer()))).await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_prefers_ndjson_from_accept() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(StreamFormat::negotiate(&headers, false), None);
+        assert_eq!(
+            StreamFormat::negotiate(&headers, true),
+            Some(StreamFormat::JsonArray)
+        );
+
+        headers.insert(
+            header::ACCEPT,
+            HeaderValue::from_static("application/json;q=0.5, application/x-ndjson"),
+        );
+        assert_eq!(
+            StreamFormat::negotiate(&headers, false),
+            Some(StreamFormat::NdJson)
+        );
+    }
+
+    #[test]
+    fn csv_rows_follow_field_order_and_quote_values() {
+        let user =
+            serde_json::json!({"id": "a1", "name": "Doe, \"JD\"", "email": "jd@example.com"});
+        let fields = ["email", "name"];
+
+        assert_eq!(StreamFormat::Csv.header(&fields), "email,name\r\n");
+        assert_eq!(
+            StreamFormat::Csv.row(0, &user, &fields),
+            "jd@example.com,\"Doe, \"\"JD\"\"\"\r\n"
+        );
+    }
+
+    #[test]
+    fn csv_cells_cannot_start_a_formula() {
+        let user = serde_json::json!({"name": "=HYPERLINK(\"http://x\")", "email": "@x"});
+        let fields = ["name", "email"];
+
+        assert_eq!(
+            StreamFormat::Csv.row(0, &user, &fields),
+            "\"'=HYPERLINK(\"\"http://x\"\")\",'@x\r\n"
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+
+use unicode_normalization::UnicodeNormalization;
+use validator::{ValidationError, ValidationErrors, ValidationErrorsKind};
+
+pub const MAX_NAME_LENGTH: u64 = 100;
+pub const MAX_EMAIL_LENGTH: u64 = 254;
+
+/// Trims surrounding whitespace and converts the value to Unicode NFC, so
+/// visually identical inputs are stored identically.
+pub fn normalize(value: &str) -> String {
+    value.trim().nfc().collect()
+}
+
+/// Canonical form used to enforce one account per email address.
+pub fn normalize_email(email: &str) -> String {
+    normalize(email).to_lowercase()
+}
+
+pub fn not_blank(value: &str) -> Result<(), ValidationError> {
+    if value.trim().is_empty() {
+        return Err(ValidationError::new("blank").with_message("must not be blank".into()));
+    }
+
+    Ok(())
+}
+
+/// Flattens validation errors into `field -> messages`, the shape returned to
+/// API clients.
+pub fn field_errors(errors: &ValidationErrors) -> BTreeMap<String, Vec<String>> {
+    errors
+        .errors()
+        .iter()
+        .filter_map(|(field, kind)| match kind {
+            ValidationErrorsKind::Field(errors) => Some((
+                field.to_string(),
+                errors.iter().map(|e| e.to_string()).collect(),
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CreateUserRequest;
+    use validator::Validate;
+
+    #[test]
+    fn normalize_trims_and_composes() {
+        assert_eq!(normalize("  Jose\u{301}  "), "Jos\u{e9}");
+    }
+
+    #[test]
+    fn create_request_reports_field_errors() {
+        let request = CreateUserRequest {
+            name: "   ".to_string(),
+            email: "not-an-email".to_string(),
+        };
+
+        let errors = field_errors(&request.validate().unwrap_err());
+
+        assert_eq!(errors["name"], vec!["must not be blank"]);
+        assert_eq!(errors["email"], vec!["must be a valid email address"]);
+    }
+
+    #[test]
+    fn create_request_rejects_long_names() {
+        let request = CreateUserRequest {
+            name: "a".repeat(MAX_NAME_LENGTH as usize + 1),
+            email: "valid@example.com".to_string(),
+        };
+
+        let errors = field_errors(&request.validate().unwrap_err());
+
+        assert!(errors.contains_key("name"));
+        assert!(!errors.contains_key("email"));
+    }
+}
@@ -1,70 +1,395 @@
 use std::sync::Arc;
 
 use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::post, Json, Router};
-use serde_json::json;
-use sqlx::Pool;
+use sqlx::{Acquire, Pool, SqliteConnection};
 
-use crate::{db, models::*};
+use crate::{
+    db,
+    envelope::{self, QueuedMessage},
+    error::ApiError,
+    lease::{LeaseError, WriterLease},
+    models::*,
+    validation::normalize_email,
+};
+
+/// Port of the HTTP mode when `PORT` is not set.
+pub const DEFAULT_PORT: u16 = 9988;
+
+/// How long processed message ids and operation statuses are kept around.
+pub const RETENTION_DAYS: i64 = 2;
 
 async fn handle_events(
     State(state): State<Arc<AppState>>,
     body: String,
-) -> Result<impl IntoResponse, WriterError> {
+) -> Result<impl IntoResponse, ApiError> {
     let event: SqsEvent = serde_json::from_str(&body).map_err(|e| {
         tracing::error!("Failed to parse SQS event: {}", e);
-        WriterError::BadRequest
+        ApiError::BadRequest("invalid event payload".to_string())
     })?;
 
+    Ok((
+        StatusCode::OK,
+        Json(process_event(&state.pool, &state.lease, &event).await),
+    ))
+}
+
+/// Applies every record of the event inside a single transaction, with a
+/// savepoint per record so a failing record is rolled back on its own. Failed
+/// records are reported so the event source mapping only retries (and
+/// eventually dead-letters) those. Nothing is applied unless the writer
+/// lease can be taken first.
+pub async fn process_event(
+    pool: &Pool<sqlx::Sqlite>,
+    lease: &WriterLease,
+    event: &SqsEvent,
+) -> SqsBatchResponse {
+    let mut response = SqsBatchResponse::default();
     let mut processed = 0u32;
-    let mut failed = 0u32;
+
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            tracing::error!("Failed to start batch transaction: {}", e);
+            return fail_all(event);
+        }
+    };
+
+    let guard = match lease.acquire(&mut tx).await {
+        Ok(guard) => guard,
+        Err(e) => {
+            tracing::error!(
+                "Not applying {} records without the writer lease: {}",
+                event.records.len(),
+                e
+            );
+            return fail_all(event);
+        }
+    };
+
+    if let Err(e) = purge_expired(&mut tx).await {
+        tracing::warn!("Failed to purge expired bookkeeping rows: {}", e);
+    }
 
     for record in &event.records {
-        let message_body = match &record.body {
-            Some(b) => b,
-            None => {
-                tracing::warn!("SQS record has no body, skipping");
-                continue;
-            }
+        let outcome = match tx.begin().await {
+            Ok(mut savepoint) => match process_record(&mut savepoint, record).await {
+                Ok(_) => savepoint.commit().await.map_err(|e| e.to_string()),
+                Err(reason) => {
+                    let _ = savepoint.rollback().await;
+                    Err(reason)
+                }
+            },
+            Err(e) => Err(format!("failed to create savepoint: {}", e)),
         };
 
-        let queued: QueuedUser = match serde_json::from_str(message_body) {
-            Ok(u) => u,
-            Err(e) => {
-                tracing::error!("Failed to parse queued user: {}", e);
-                failed += 1;
-                continue;
+        match outcome {
+            Ok(_) => processed += 1,
+            Err(reason) => {
+                tracing::error!(
+                    "Failed to process message {:?}: {}",
+                    record.message_id,
+                    reason
+                );
+                record_failure(&mut tx, record, &reason).await;
+                response
+                    .batch_item_failures
+                    .extend(batch_item_failure(record));
             }
-        };
+        }
+    }
 
-        match insert_user(&state.pool, &queued).await {
-            Ok(_) => {
-                processed += 1;
-                tracing::info!("Inserted user {} ({})", queued.id, queued.name);
-            }
-            Err(e) => {
-                tracing::error!("Failed to insert user {}: {}", queued.id, e);
-                failed += 1;
-            }
+    if let Err(e) = guard.commit(tx).await {
+        tracing::error!("Failed to commit batch transaction: {}", e);
+        return fail_all(event);
+    }
+
+    tracing::info!(
+        "Processed {} records, {} failed",
+        processed,
+        response.batch_item_failures.len()
+    );
+
+    response
+}
+
+/// Reports every record as failed, used when the batch transaction itself
+/// could not be started or committed.
+fn fail_all(event: &SqsEvent) -> SqsBatchResponse {
+    SqsBatchResponse {
+        batch_item_failures: event
+            .records
+            .iter()
+            .filter_map(batch_item_failure)
+            .collect(),
+    }
+}
+
+fn batch_item_failure(record: &SqsRecord) -> Option<SqsBatchItemFailure> {
+    if record.message_id.is_none() {
+        tracing::error!("Failed record has no messageId, it cannot be retried");
+    }
+
+    record
+        .message_id
+        .clone()
+        .map(|item_identifier| SqsBatchItemFailure { item_identifier })
+}
+
+async fn process_record(conn: &mut SqliteConnection, record: &SqsRecord) -> Result<(), String> {
+    if let Some(message_id) = &record.message_id {
+        if is_processed(conn, message_id)
+            .await
+            .map_err(|e| e.to_string())?
+        {
+            tracing::info!("Message {} was already applied, skipping", message_id);
+            return Ok(());
         }
     }
 
-    Ok((
-        StatusCode::OK,
-        Json(json!({
-            "processed": processed,
-            "failed": failed,
-        })),
-    ))
+    let message_body = record
+        .body
+        .as_ref()
+        .ok_or_else(|| "record has no body".to_string())?;
+
+    let QueuedMessage {
+        operation_id,
+        operation,
+    } = envelope::decode_message(message_body).map_err(|e| e.to_string())?;
+    operation
+        .validate()
+        .map_err(|e| format!("invalid {} payload: {}", operation.operation_type(), e))?;
+
+    apply_operation(conn, &operation).await.map_err(|e| {
+        format!(
+            "failed to apply {} for user {}: {}",
+            operation.operation_type(),
+            operation.user_id(),
+            e
+        )
+    })?;
+
+    if let Some(message_id) = &record.message_id {
+        mark_processed(conn, message_id)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    record_operation(
+        conn,
+        operation_id.as_deref().or(record.message_id.as_deref()),
+        &operation,
+        OperationStatus::Applied,
+        None,
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+
+    tracing::info!(
+        "Applied {} for user {}",
+        operation.operation_type(),
+        operation.user_id()
+    );
+
+    Ok(())
+}
+
+async fn is_processed(conn: &mut SqliteConnection, message_id: &str) -> Result<bool, sqlx::Error> {
+    let found: Option<i64> =
+        sqlx::query_scalar("SELECT 1 FROM processed_messages WHERE message_id = $1")
+            .bind(message_id)
+            .fetch_optional(conn)
+            .await?;
+
+    Ok(found.is_some())
+}
+
+async fn mark_processed(conn: &mut SqliteConnection, message_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO processed_messages (message_id) VALUES ($1)")
+        .bind(message_id)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Forgets processed message ids and operation statuses older than the
+/// retention window. SQS drops messages after the queue retention period, so
+/// they cannot be redelivered.
+async fn purge_expired(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM processed_messages WHERE processed_at < datetime('now', $1)")
+        .bind(format!("-{} days", RETENTION_DAYS))
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query("DELETE FROM operations WHERE updated_at < datetime('now', $1)")
+        .bind(format!("-{} days", RETENTION_DAYS))
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Upserts the status of an operation under its id: the one the API returned,
+/// or the queue message id for messages queued without one.
+async fn record_operation(
+    conn: &mut SqliteConnection,
+    operation_id: Option<&str>,
+    operation: &QueuedOperation,
+    status: OperationStatus,
+    failure_reason: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO operations (operation_id, user_id, operation, status, failure_reason) VALUES ($1, $2, $3, $4, $5)
+         ON CONFLICT (operation_id) DO UPDATE SET status = excluded.status, failure_reason = excluded.failure_reason, updated_at = CURRENT_TIMESTAMP",
+    )
+    .bind(operation_id)
+    .bind(operation.user_id())
+    .bind(operation.operation_type())
+    .bind(status)
+    .bind(failure_reason)
+    .execute(conn)
+    .await?;
+
+    Ok(())
 }
 
-async fn insert_user(pool: &Pool<sqlx::Sqlite>, user: &QueuedUser) -> Result<(), sqlx::Error> {
-    sqlx::query("INSERT INTO users (id, name, email) VALUES ($1, $2, $3)")
+/// Marks the operation carried by a failed record as failed, outside of the
+/// record's rolled back savepoint. Records that cannot be decoded are not
+/// attributable to a user and are only logged.
+async fn record_failure(conn: &mut SqliteConnection, record: &SqsRecord, reason: &str) {
+    let message = match record
+        .body
+        .as_deref()
+        .and_then(|body| envelope::decode_message(body).ok())
+    {
+        Some(message) => message,
+        None => return,
+    };
+
+    let operation_id = message
+        .operation_id
+        .as_deref()
+        .or(record.message_id.as_deref());
+    if let Err(e) = record_operation(
+        conn,
+        operation_id,
+        &message.operation,
+        OperationStatus::Failed,
+        Some(reason),
+    )
+    .await
+    {
+        tracing::warn!(
+            "Failed to record failure of operation {:?}: {}",
+            operation_id,
+            e
+        );
+    }
+}
+
+/// Applies an operation without going through the queue, recording it as
+/// applied under `operation_id`. Used by the API when no queue is configured.
+pub async fn apply_direct(
+    pool: &Pool<sqlx::Sqlite>,
+    lease: &WriterLease,
+    operation_id: &str,
+    operation: &QueuedOperation,
+) -> Result<(), ApplyError> {
+    let mut tx = pool.begin().await?;
+
+    let guard = lease.acquire(&mut tx).await.map_err(ApplyError::Lease)?;
+
+    apply_operation(&mut tx, operation).await?;
+    record_operation(
+        &mut tx,
+        Some(operation_id),
+        operation,
+        OperationStatus::Applied,
+        None,
+    )
+    .await?;
+
+    guard.commit(tx).await.map_err(ApplyError::Lease)?;
+
+    Ok(())
+}
+
+pub async fn apply_operation(
+    conn: &mut SqliteConnection,
+    operation: &QueuedOperation,
+) -> Result<(), ApplyError> {
+    match operation {
+        QueuedOperation::CreateUser(user) => insert_user(conn, user).await,
+        QueuedOperation::UpdateUser(update) => update_user(conn, update).await,
+        QueuedOperation::DeleteUser(delete) => delete_user(conn, delete).await,
+    }
+}
+
+/// Inserts the user. A row that already exists with the same data is a replay
+/// of this creation and succeeds; any other existing row is a conflict.
+async fn insert_user(conn: &mut SqliteConnection, user: &QueuedUser) -> Result<(), ApplyError> {
+    let result = sqlx::query(
+        "INSERT INTO users (id, name, email, email_normalized) VALUES ($1, $2, $3, $4) ON CONFLICT (id) DO NOTHING",
+    )
+    .bind(&user.id)
+    .bind(&user.name)
+    .bind(&user.email)
+    .bind(normalize_email(&user.email))
+    .execute(&mut *conn)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        let existing = sqlx::query_as::<_, User>(
+            "SELECT id, name, email, created_at, updated_at FROM users WHERE id = $1",
+        )
         .bind(&user.id)
-        .bind(&user.name)
-        .bind(&user.email)
-        .execute(pool)
+        .fetch_one(&mut *conn)
         .await?;
 
+        if existing.name != user.name || existing.email != user.email {
+            return Err(ApplyError::Conflict);
+        }
+
+        tracing::info!("User {} already exists with the same data", user.id);
+    }
+
+    Ok(())
+}
+
+async fn update_user(
+    conn: &mut SqliteConnection,
+    update: &QueuedUserUpdate,
+) -> Result<(), ApplyError> {
+    let result = sqlx::query(
+        "UPDATE users SET name = COALESCE($2, name), email = COALESCE($3, email), email_normalized = COALESCE($4, email_normalized), updated_at = CURRENT_TIMESTAMP WHERE id = $1",
+    )
+    .bind(&update.id)
+    .bind(&update.name)
+    .bind(&update.email)
+    .bind(update.email.as_deref().map(normalize_email))
+    .execute(conn)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApplyError::NotFound);
+    }
+
+    Ok(())
+}
+
+async fn delete_user(
+    conn: &mut SqliteConnection,
+    delete: &QueuedUserDelete,
+) -> Result<(), ApplyError> {
+    let result = sqlx::query("DELETE FROM users WHERE id = $1")
+        .bind(&delete.id)
+        .execute(conn)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApplyError::NotFound);
+    }
+
     Ok(())
 }
 
@@ -73,12 +398,9 @@ pub fn create_router() -> Router<Arc<AppState>> {
 }
 
 pub async fn serve_writer(state: Arc<AppState>) {
-    tracing_subscriber::fmt::init();
+    let port = state.config.port.unwrap_or(DEFAULT_PORT);
 
-    db::set_default_env_var("PORT", "9988");
-    let port = std::env::var("PORT").expect("Application port not defined");
-
-    let address = std::net::SocketAddr::from(([0, 0, 0, 0], port.parse().unwrap()));
+    let address = std::net::SocketAddr::from(([0, 0, 0, 0], port));
     let listener = tokio::net::TcpListener::bind(address).await.unwrap();
     tracing::info!("Writer listening on {}", address);
 
@@ -88,22 +410,44 @@ pub async fn serve_writer(state: Arc<AppState>) {
         .unwrap();
 }
 
-enum WriterError {
-    BadRequest,
+#[derive(Debug)]
+pub enum ApplyError {
+    NotFound,
+    Conflict,
+    EmailTaken,
+    Lease(LeaseError),
+    Database(sqlx::Error),
 }
 
-impl IntoResponse for WriterError {
-    fn into_response(self) -> axum::response::Response {
-        let (status, body) = match self {
-            WriterError::BadRequest => (StatusCode::BAD_REQUEST, "invalid event payload"),
-        };
-        (status, Json(json!({ "message": body }))).into_response()
+impl std::fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApplyError::NotFound => write!(f, "user not found"),
+            ApplyError::Conflict => write!(f, "user already exists with different data"),
+            ApplyError::EmailTaken => write!(f, "email address is already taken"),
+            ApplyError::Lease(e) => write!(f, "{}", e),
+            ApplyError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for ApplyError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::Database(db)
+                if db.is_unique_violation() && db.message().contains("email_normalized") =>
+            {
+                ApplyError::EmailTaken
+            }
+            _ => ApplyError::Database(e),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json::json;
 
     #[tokio::test]
     async fn parse_valid_sqs_event() {
@@ -139,16 +483,345 @@ mod tests {
             email: "int@example.com".to_string(),
         };
 
-        insert_user(&pool, &user).await.unwrap();
+        let mut conn = pool.acquire().await.unwrap();
+        insert_user(&mut conn, &user).await.unwrap();
 
-        let row = sqlx::query_as::<_, User>("SELECT id, name, email FROM users WHERE id = $1")
-            .bind(&user.id)
-            .fetch_one(&pool)
-            .await
-            .unwrap();
+        let row = sqlx::query_as::<_, User>(
+            "SELECT id, name, email, created_at, updated_at FROM users WHERE id = $1",
+        )
+        .bind(&user.id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
 
         assert_eq!(row.id, user.id);
         assert_eq!(row.name, user.name);
         assert_eq!(row.email, user.email);
     }
+
+    #[sqlx::test]
+    async fn update_and_delete_user_in_db(pool: sqlx::SqlitePool) {
+        let mut conn = pool.acquire().await.unwrap();
+        let user = QueuedUser {
+            id: "test-id-002".to_string(),
+            name: "before".to_string(),
+            email: "before@example.com".to_string(),
+        };
+        apply_operation(&mut conn, &QueuedOperation::CreateUser(user.clone()))
+            .await
+            .unwrap();
+
+        let update = QueuedUserUpdate {
+            id: user.id.clone(),
+            name: None,
+            email: Some("after@example.com".to_string()),
+        };
+        apply_operation(&mut conn, &QueuedOperation::UpdateUser(update))
+            .await
+            .unwrap();
+
+        let row = sqlx::query_as::<_, User>(
+            "SELECT id, name, email, created_at, updated_at FROM users WHERE id = $1",
+        )
+        .bind(&user.id)
+        .fetch_one(&mut *conn)
+        .await
+        .unwrap();
+        assert_eq!(row.name, "before");
+        assert_eq!(row.email, "after@example.com");
+
+        let delete = QueuedOperation::DeleteUser(QueuedUserDelete {
+            id: user.id.clone(),
+        });
+        apply_operation(&mut conn, &delete).await.unwrap();
+
+        let err = apply_operation(&mut conn, &delete).await.unwrap_err();
+        assert!(matches!(err, ApplyError::NotFound));
+    }
+
+    fn sqs_record(message_id: &str, body: &str) -> SqsRecord {
+        SqsRecord {
+            message_id: Some(message_id.to_string()),
+            receipt_handle: None,
+            body: Some(body.to_string()),
+            attributes: None,
+            message_attributes: None,
+            md5_of_body: None,
+            event_source: Some("aws:sqs".to_string()),
+            event_source_arn: None,
+            aws_region: None,
+        }
+    }
+
+    #[sqlx::test]
+    async fn failed_records_are_reported_as_batch_item_failures(pool: sqlx::SqlitePool) {
+        let lease = WriterLease::new(Default::default());
+        let event = SqsEvent {
+            records: vec![
+                sqs_record(
+                    "created",
+                    r#"{"id":"batch-001","name":"ok","email":"ok@example.com"}"#,
+                ),
+                sqs_record("garbage", "not json"),
+                sqs_record(
+                    "duplicate",
+                    r#"{"id":"batch-001","name":"dup","email":"dup@example.com"}"#,
+                ),
+            ],
+        };
+
+        let response = process_event(&pool, &lease, &event).await;
+
+        let body = serde_json::to_value(&response).unwrap();
+        assert_eq!(
+            body,
+            json!({ "batchItemFailures": [
+                { "itemIdentifier": "garbage" },
+                { "itemIdentifier": "duplicate" },
+            ]})
+        );
+
+        let row = sqlx::query_as::<_, User>(
+            "SELECT id, name, email, created_at, updated_at FROM users WHERE id = $1",
+        )
+        .bind("batch-001")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(row.name, "ok");
+    }
+
+    #[sqlx::test]
+    async fn batch_is_applied_in_one_transaction(pool: sqlx::SqlitePool) {
+        let lease = WriterLease::new(Default::default());
+        let event = SqsEvent {
+            records: vec![
+                sqs_record(
+                    "first",
+                    r#"{"id":"tx-001","name":"first","email":"first@example.com"}"#,
+                ),
+                sqs_record(
+                    "update-missing",
+                    &envelope::encode_message(
+                        "op-update",
+                        &QueuedOperation::UpdateUser(QueuedUserUpdate {
+                            id: "tx-404".to_string(),
+                            name: Some("nobody".to_string()),
+                            email: None,
+                        }),
+                    )
+                    .unwrap(),
+                ),
+                sqs_record(
+                    "second",
+                    r#"{"id":"tx-002","name":"second","email":"second@example.com"}"#,
+                ),
+            ],
+        };
+
+        let response = process_event(&pool, &lease, &event).await;
+        assert_eq!(
+            response.batch_item_failures,
+            vec![SqsBatchItemFailure {
+                item_identifier: "update-missing".to_string()
+            }]
+        );
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE id LIKE 'tx-%'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[sqlx::test]
+    async fn redelivered_record_is_applied_once(pool: sqlx::SqlitePool) {
+        let lease = WriterLease::new(Default::default());
+        let delete = envelope::encode_message(
+            "op-delete",
+            &QueuedOperation::DeleteUser(QueuedUserDelete {
+                id: "replay-001".to_string(),
+            }),
+        )
+        .unwrap();
+        let event = SqsEvent {
+            records: vec![
+                sqs_record(
+                    "create",
+                    r#"{"id":"replay-001","name":"replay","email":"replay@example.com"}"#,
+                ),
+                sqs_record("delete", &delete),
+            ],
+        };
+
+        let response = process_event(&pool, &lease, &event).await;
+        assert!(response.batch_item_failures.is_empty());
+
+        // The same records delivered again must not fail on the missing row.
+        let response = process_event(&pool, &lease, &event).await;
+        assert!(response.batch_item_failures.is_empty());
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE id = 'replay-001'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[sqlx::test]
+    async fn duplicate_creation_is_a_replay_unless_data_differs(pool: sqlx::SqlitePool) {
+        let lease = WriterLease::new(Default::default());
+        let body = r#"{"id":"replay-002","name":"replay","email":"replay@example.com"}"#;
+        let event = SqsEvent {
+            records: vec![
+                sqs_record("first-publish", body),
+                sqs_record("second-publish", body),
+                sqs_record(
+                    "conflict",
+                    r#"{"id":"replay-002","name":"other","email":"other@example.com"}"#,
+                ),
+            ],
+        };
+
+        let response = process_event(&pool, &lease, &event).await;
+        assert_eq!(
+            response.batch_item_failures,
+            vec![SqsBatchItemFailure {
+                item_identifier: "conflict".to_string()
+            }]
+        );
+    }
+
+    #[sqlx::test]
+    async fn operation_statuses_are_recorded(pool: sqlx::SqlitePool) {
+        let lease = WriterLease::new(Default::default());
+        let event = SqsEvent {
+            records: vec![
+                sqs_record(
+                    "applied",
+                    r#"{"id":"status-001","name":"ok","email":"ok@example.com"}"#,
+                ),
+                sqs_record(
+                    "failed",
+                    &envelope::encode_message(
+                        "op-404",
+                        &QueuedOperation::DeleteUser(QueuedUserDelete {
+                            id: "status-404".to_string(),
+                        }),
+                    )
+                    .unwrap(),
+                ),
+            ],
+        };
+
+        process_event(&pool, &lease, &event).await;
+
+        let rows: Vec<(String, String, String, OperationStatus, Option<String>)> =
+            sqlx::query_as(
+                "SELECT operation_id, user_id, operation, status, failure_reason FROM operations ORDER BY id",
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(rows.len(), 2);
+        // A bare QueuedUser has no operation id and is keyed by its message id.
+        assert_eq!(rows[0].0, "applied");
+        assert_eq!(rows[0].1, "status-001");
+        assert_eq!(rows[0].2, "create");
+        assert_eq!(rows[0].3, OperationStatus::Applied);
+        assert_eq!(rows[0].4, None);
+        assert_eq!(rows[1].0, "op-404");
+        assert_eq!(rows[1].1, "status-404");
+        assert_eq!(rows[1].3, OperationStatus::Failed);
+        assert!(rows[1].4.as_deref().unwrap().contains("user not found"));
+    }
+
+    #[sqlx::test]
+    async fn invalid_queued_user_is_rejected(pool: sqlx::SqlitePool) {
+        let lease = WriterLease::new(Default::default());
+        let event = SqsEvent {
+            records: vec![sqs_record(
+                "invalid",
+                r#"{"id":"invalid-001","name":"","email":"not-an-email"}"#,
+            )],
+        };
+
+        let response = process_event(&pool, &lease, &event).await;
+        assert_eq!(
+            response.batch_item_failures,
+            vec![SqsBatchItemFailure {
+                item_identifier: "invalid".to_string()
+            }]
+        );
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[sqlx::test]
+    async fn email_addresses_are_unique_ignoring_case(pool: sqlx::SqlitePool) {
+        let lease = WriterLease::new(Default::default());
+        let event = SqsEvent {
+            records: vec![
+                sqs_record(
+                    "first",
+                    r#"{"id":"email-001","name":"first","email":"Taken@Example.com"}"#,
+                ),
+                sqs_record(
+                    "second",
+                    r#"{"id":"email-002","name":"second","email":"taken@example.com"}"#,
+                ),
+            ],
+        };
+
+        let response = process_event(&pool, &lease, &event).await;
+        assert_eq!(
+            response.batch_item_failures,
+            vec![SqsBatchItemFailure {
+                item_identifier: "second".to_string()
+            }]
+        );
+
+        let reason: Option<String> =
+            sqlx::query_scalar("SELECT failure_reason FROM operations WHERE user_id = 'email-002'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert!(reason.unwrap().contains("email address is already taken"));
+    }
+
+    #[sqlx::test]
+    async fn batch_is_refused_while_another_writer_holds_the_lease(pool: sqlx::SqlitePool) {
+        let holder = WriterLease::new(Default::default());
+        let mut tx = pool.begin().await.unwrap();
+        holder.acquire(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+
+        let lease = WriterLease::new(Default::default());
+        let event = SqsEvent {
+            records: vec![sqs_record(
+                "fenced",
+                r#"{"id":"lease-001","name":"fenced","email":"fenced@example.com"}"#,
+            )],
+        };
+
+        let response = process_event(&pool, &lease, &event).await;
+        assert_eq!(
+            response.batch_item_failures,
+            vec![SqsBatchItemFailure {
+                item_identifier: "fenced".to_string()
+            }]
+        );
+        assert_eq!(lease.contended(), 1);
+
+        let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(users, 0);
+    }
 }
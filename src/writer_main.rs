@@ -1,7 +1,22 @@
-use lambda_rust_sqlite3_efs::{db, writer};
+use lambda_rust_sqlite3_efs::{
+    config::{Config, WriterMode},
+    db::{self, DatabaseAccess},
+    poller, writer,
+};
 
 #[tokio::main]
 async fn main() {
-    let state = db::bootstrap().await;
-    writer::serve_writer(state).await;
+    let config = Config::load().unwrap_or_else(|e| panic!("Invalid configuration: {}", e));
+    let state = db::bootstrap(config, DatabaseAccess::ReadWrite).await;
+
+    match state.config.writer_mode {
+        WriterMode::Http => writer::serve_writer(state).await,
+        WriterMode::Poll => poller::serve_poller(state).await,
+        #[cfg(feature = "lambda-runtime")]
+        WriterMode::Lambda => lambda_rust_sqlite3_efs::lambda::run_writer(state)
+            .await
+            .expect("Lambda runtime failed"),
+        #[cfg(not(feature = "lambda-runtime"))]
+        WriterMode::Lambda => unreachable!("rejected when the configuration is loaded"),
+    }
 }